@@ -0,0 +1,2 @@
+DROP TABLE sleep_stages;
+DROP TABLE sleeps;
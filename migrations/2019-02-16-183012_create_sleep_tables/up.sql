@@ -0,0 +1,26 @@
+CREATE TABLE sleeps (
+  start_time      TIMESTAMPTZ   NOT NULL,
+  end_time        TIMESTAMPTZ   NOT NULL,
+  user_id         UUID          REFERENCES users(id) NOT NULL,
+  source          TEXT          NOT NULL, /* probably just fitbit... */
+  log_id          BIGINT        NOT NULL,
+  is_main_sleep   BOOLEAN       NOT NULL,
+  efficiency      INTEGER       CHECK (efficiency >= 0) NOT NULL,
+  minutes_asleep  INTEGER       CHECK (minutes_asleep >= 0) NOT NULL,
+  minutes_awake   INTEGER       CHECK (minutes_awake >= 0) NOT NULL,
+  time_in_bed     INTEGER       CHECK (time_in_bed >= 0) NOT NULL,
+  PRIMARY KEY (user_id, start_time)
+);
+CREATE INDEX ON sleeps (user_id, start_time DESC);
+SELECT create_hypertable('sleeps', 'start_time');
+
+CREATE TABLE sleep_stages (
+  time    TIMESTAMPTZ   NOT NULL,
+  user_id UUID          REFERENCES users(id) NOT NULL,
+  source  TEXT          NOT NULL, /* probably just fitbit... */
+  level   TEXT          NOT NULL, /* deep, light, rem, wake - or asleep, restless, awake for classic logs */
+  seconds INTEGER       CHECK (seconds >= 0) NOT NULL,
+  PRIMARY KEY (user_id, time)
+);
+CREATE INDEX ON sleep_stages (user_id, time DESC);
+SELECT create_hypertable('sleep_stages', 'time');
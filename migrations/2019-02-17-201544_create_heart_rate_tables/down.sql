@@ -0,0 +1,3 @@
+DROP TABLE heart_rate_zones;
+DROP TABLE resting_heart_rates;
+DROP TABLE heart_rates;
@@ -0,0 +1,35 @@
+CREATE TABLE heart_rates (
+  time    TIMESTAMPTZ   NOT NULL,
+  user_id UUID          REFERENCES users(id) NOT NULL,
+  source  TEXT          NOT NULL, /* probably just fitbit... */
+  count   INTEGER       CHECK (count >= 0) NOT NULL,
+  PRIMARY KEY (user_id, time)
+);
+CREATE INDEX ON heart_rates (user_id, time DESC);
+SELECT create_hypertable('heart_rates', 'time');
+
+/* one row per day, stamped at local midnight */
+CREATE TABLE resting_heart_rates (
+  time    TIMESTAMPTZ   NOT NULL,
+  user_id UUID          REFERENCES users(id) NOT NULL,
+  source  TEXT          NOT NULL, /* probably just fitbit... */
+  count   INTEGER       CHECK (count >= 0) NOT NULL,
+  PRIMARY KEY (user_id, time)
+);
+CREATE INDEX ON resting_heart_rates (user_id, time DESC);
+SELECT create_hypertable('resting_heart_rates', 'time');
+
+/* one row per day per zone (Out of Range, Fat Burn, Cardio, Peak), stamped at local midnight */
+CREATE TABLE heart_rate_zones (
+  time          TIMESTAMPTZ       NOT NULL,
+  user_id       UUID              REFERENCES users(id) NOT NULL,
+  source        TEXT              NOT NULL, /* probably just fitbit... */
+  name          TEXT              NOT NULL,
+  min           INTEGER           NOT NULL,
+  max           INTEGER           NOT NULL,
+  minutes       INTEGER           CHECK (minutes >= 0) NOT NULL,
+  calories_out  DOUBLE PRECISION  CHECK (calories_out >= 0) NOT NULL,
+  PRIMARY KEY (user_id, time, name)
+);
+CREATE INDEX ON heart_rate_zones (user_id, time DESC);
+SELECT create_hypertable('heart_rate_zones', 'time');
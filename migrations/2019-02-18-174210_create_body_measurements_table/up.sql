@@ -0,0 +1,10 @@
+CREATE TABLE body_measurements (
+  time    TIMESTAMPTZ       NOT NULL,
+  user_id UUID              REFERENCES users(id) NOT NULL,
+  kind    TEXT              NOT NULL, /* weight (kg), bmi, fat (%) */
+  source  TEXT              NOT NULL, /* probably just fitbit... */
+  value   DOUBLE PRECISION  CHECK (value >= 0) NOT NULL,
+  PRIMARY KEY (user_id, time, kind)
+);
+CREATE INDEX ON body_measurements (user_id, kind, time DESC);
+SELECT create_hypertable('body_measurements', 'time');
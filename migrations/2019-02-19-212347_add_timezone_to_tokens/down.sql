@@ -0,0 +1,3 @@
+ALTER TABLE tokens
+  DROP COLUMN IF EXISTS timezone,
+  DROP COLUMN IF EXISTS utc_offset_ms;
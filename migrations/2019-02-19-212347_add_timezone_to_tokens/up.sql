@@ -0,0 +1,4 @@
+/* from the provider's profile (only fitbit has one), refreshed along with the access token */
+ALTER TABLE tokens
+  ADD COLUMN timezone TEXT,
+  ADD COLUMN utc_offset_ms INTEGER;
@@ -0,0 +1,12 @@
+DROP MATERIALIZED VIEW steps_hourly;
+DROP MATERIALIZED VIEW steps_daily;
+DROP MATERIALIZED VIEW calories_hourly;
+DROP MATERIALIZED VIEW calories_daily;
+DROP MATERIALIZED VIEW distances_hourly;
+DROP MATERIALIZED VIEW distances_daily;
+DROP MATERIALIZED VIEW elevations_hourly;
+DROP MATERIALIZED VIEW elevations_daily;
+DROP MATERIALIZED VIEW floors_hourly;
+DROP MATERIALIZED VIEW floors_daily;
+DROP MATERIALIZED VIEW heart_rates_hourly;
+DROP MATERIALIZED VIEW heart_rates_daily;
@@ -0,0 +1,50 @@
+/*
+ * Hourly and daily rollups of every intraday table, for long-range queries. The daily ones are
+ * UTC days, other timezones re-bucket the hourly ones. Rows older than the policy window are only
+ * materialized when the worker refreshes the days it ingests, so after migrating a database that
+ * already has data, run `CALL refresh_continuous_aggregate('<view>', NULL, NULL);` once per view.
+ *
+ * Needs TimescaleDB 2, for real-time aggregates and the refresh policies and procedure.
+ */
+
+DO $$
+DECLARE
+  _tbl TEXT;
+  _rollup RECORD;
+BEGIN
+  IF (SELECT split_part(extversion, '.', 1)::INTEGER FROM pg_extension
+      WHERE extname = 'timescaledb') < 2 THEN
+    RAISE EXCEPTION 'intraday rollups need TimescaleDB 2 or newer';
+  END IF;
+
+  FOREACH _tbl IN ARRAY
+    ARRAY['steps', 'calories', 'distances', 'elevations', 'floors', 'heart_rates']
+  LOOP
+    FOR _rollup IN
+      SELECT * FROM (VALUES
+        ('hourly', INTERVAL '1 hour', INTERVAL '3 days', INTERVAL '30 minutes'),
+        ('daily',  INTERVAL '1 day',  INTERVAL '4 days', INTERVAL '1 hour')
+      ) AS r (suffix, width, start_offset, schedule_interval)
+    LOOP
+      EXECUTE format(
+        'CREATE MATERIALIZED VIEW %1$I
+         WITH (timescaledb.continuous, timescaledb.materialized_only = false) AS
+         SELECT user_id,
+                time_bucket(%3$L, time) AS bucket,
+                SUM(count)              AS total,
+                MIN(count)              AS minimum,
+                MAX(count)              AS maximum,
+                COUNT(*)                AS samples
+         FROM %2$I
+         GROUP BY user_id, bucket
+         WITH NO DATA',
+        _tbl || '_' || _rollup.suffix, _tbl, _rollup.width);
+      EXECUTE format('CREATE INDEX ON %I (user_id, bucket DESC)', _tbl || '_' || _rollup.suffix);
+      PERFORM add_continuous_aggregate_policy(_tbl || '_' || _rollup.suffix,
+        start_offset      => _rollup.start_offset,
+        end_offset        => INTERVAL '1 hour',
+        schedule_interval => _rollup.schedule_interval);
+    END LOOP;
+  END LOOP;
+END
+$$;
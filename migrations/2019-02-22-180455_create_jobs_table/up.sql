@@ -0,0 +1,14 @@
+CREATE TABLE jobs (
+  id          UUID          PRIMARY KEY,
+  user_id     UUID          REFERENCES users(id) NOT NULL,
+  parent_id   UUID          REFERENCES jobs(id), /* the bulk job that enqueued this one */
+  params      TEXT          NOT NULL, /* QueueActionParams as json */
+  status      TEXT          NOT NULL, /* queued, running, retrying, succeeded or failed */
+  attempts    INTEGER       NOT NULL DEFAULT 0,
+  error       TEXT,
+  created_at  TIMESTAMPTZ   NOT NULL DEFAULT NOW(),
+  started_at  TIMESTAMPTZ,
+  finished_at TIMESTAMPTZ
+);
+CREATE INDEX ON jobs (user_id, created_at DESC);
+CREATE INDEX ON jobs (parent_id);
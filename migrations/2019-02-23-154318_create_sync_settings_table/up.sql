@@ -0,0 +1,10 @@
+/* how often the scheduler syncs a connected service, and which metrics */
+CREATE TABLE sync_settings (
+  user_id           UUID          REFERENCES users(id) NOT NULL,
+  service           TEXT          NOT NULL,
+  interval_minutes  INTEGER       CHECK (interval_minutes >= 15) NOT NULL DEFAULT 60,
+  metrics           TEXT[]        NOT NULL DEFAULT '{step,calorie,distance,elevation,floor,heart_rate}',
+  enabled           BOOLEAN       NOT NULL DEFAULT TRUE,
+  last_scheduled_at TIMESTAMPTZ,
+  PRIMARY KEY (user_id, service)
+);
@@ -0,0 +1,9 @@
+/* the last day of each metric that was synced completely */
+CREATE TABLE sync_cursors (
+  user_id         UUID          REFERENCES users(id) NOT NULL,
+  service         TEXT          NOT NULL,
+  metric          TEXT          NOT NULL,
+  synced_through  DATE          NOT NULL,
+  updated_at      TIMESTAMPTZ   NOT NULL DEFAULT NOW(),
+  PRIMARY KEY (user_id, service, metric)
+);
@@ -0,0 +1,5 @@
+/* encrypted tokens can't be read without these, so the users have to connect again */
+DELETE FROM tokens WHERE key_id IS NOT NULL;
+ALTER TABLE tokens
+  DROP COLUMN IF EXISTS data_key,
+  DROP COLUMN IF EXISTS key_id;
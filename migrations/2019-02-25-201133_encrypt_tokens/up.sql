@@ -0,0 +1,5 @@
+/* access and refresh tokens are sealed under data_key, which is sealed under the master key
+   key_id. rows with a NULL key_id are still plaintext until `qs-worker encrypt-tokens` runs */
+ALTER TABLE tokens
+  ADD COLUMN data_key TEXT,
+  ADD COLUMN key_id TEXT;
@@ -0,0 +1,4 @@
+ALTER TABLE tokens
+  DROP COLUMN IF EXISTS scopes,
+  DROP COLUMN IF EXISTS refresh_error,
+  DROP COLUMN IF EXISTS refresh_failed_at;
@@ -0,0 +1,6 @@
+/* scopes are what the user actually granted, which can be less than we asked for.
+   refresh_error is set when refreshing the tokens failed, until they work again */
+ALTER TABLE tokens
+  ADD COLUMN scopes            TEXT[] NOT NULL DEFAULT '{}',
+  ADD COLUMN refresh_error     TEXT,
+  ADD COLUMN refresh_failed_at TIMESTAMPTZ;
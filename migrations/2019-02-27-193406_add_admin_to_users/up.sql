@@ -0,0 +1,3 @@
+/* admins can read every user's data */
+ALTER TABLE users
+  ADD COLUMN is_admin BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,15 @@
+/* read-only access to some of owner's metrics for grantee, limited to the data between
+   start_time and end_time (unbounded when NULL) until expires_at (never when NULL) */
+CREATE TABLE grants (
+  id          UUID          PRIMARY KEY,
+  owner_id    UUID          REFERENCES users(id) NOT NULL,
+  grantee_id  UUID          REFERENCES users(id) NOT NULL,
+  metrics     TEXT[]        NOT NULL,
+  start_time  TIMESTAMPTZ,
+  end_time    TIMESTAMPTZ,
+  expires_at  TIMESTAMPTZ,
+  created_at  TIMESTAMPTZ   NOT NULL DEFAULT NOW(),
+  CHECK (owner_id <> grantee_id)
+);
+CREATE INDEX ON grants (owner_id, grantee_id);
+CREATE INDEX ON grants (grantee_id);
@@ -0,0 +1,14 @@
+/* personal access tokens for calling /graphql without the login cookie. only a sha-256 of the
+   token is kept, prefix is its first few characters so people can tell their tokens apart */
+CREATE TABLE access_tokens (
+  id           UUID          PRIMARY KEY,
+  user_id      UUID          REFERENCES users(id) NOT NULL,
+  name         TEXT          NOT NULL,
+  prefix       TEXT          NOT NULL,
+  token_hash   TEXT          NOT NULL UNIQUE,
+  scope        TEXT          NOT NULL, /* read or read_write */
+  created_at   TIMESTAMPTZ   NOT NULL DEFAULT NOW(),
+  expires_at   TIMESTAMPTZ,
+  last_used_at TIMESTAMPTZ
+);
+CREATE INDEX ON access_tokens (user_id);
@@ -0,0 +1,10 @@
+/* users without a google identity are left with a NULL g_sub */
+ALTER TABLE users
+  ADD COLUMN g_sub TEXT UNIQUE;
+
+UPDATE users
+  SET g_sub = identities.subject
+  FROM identities
+  WHERE identities.user_id = users.id AND identities.provider = 'google';
+
+DROP TABLE identities;
@@ -0,0 +1,17 @@
+/* the accounts a user can log in with. subject is the provider's id for them, which never
+   changes, unlike their email */
+CREATE TABLE identities (
+  provider    TEXT          NOT NULL,
+  subject     TEXT          NOT NULL,
+  user_id     UUID          REFERENCES users(id) NOT NULL,
+  email       TEXT,
+  created_at  TIMESTAMPTZ   NOT NULL DEFAULT NOW(),
+  PRIMARY KEY (provider, subject)
+);
+CREATE INDEX ON identities (user_id);
+
+INSERT INTO identities (provider, subject, user_id, email)
+  SELECT 'google', g_sub, id, email FROM users;
+
+ALTER TABLE users
+  DROP COLUMN IF EXISTS g_sub;
@@ -0,0 +1 @@
+DROP TABLE heart_rate_minutes;
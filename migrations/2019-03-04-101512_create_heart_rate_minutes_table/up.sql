@@ -0,0 +1,11 @@
+/* the same heart rate as heart_rates, averaged per minute. Charts over more than a few hours
+   read this instead of 86,400 rows a day */
+CREATE TABLE heart_rate_minutes (
+  time    TIMESTAMPTZ   NOT NULL,
+  user_id UUID          REFERENCES users(id) NOT NULL,
+  source  TEXT          NOT NULL, /* probably just fitbit... */
+  count   INTEGER       CHECK (count >= 0) NOT NULL,
+  PRIMARY KEY (user_id, time)
+);
+CREATE INDEX ON heart_rate_minutes (user_id, time DESC);
+SELECT create_hypertable('heart_rate_minutes', 'time');
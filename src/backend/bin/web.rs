@@ -0,0 +1,122 @@
+#[macro_use]
+extern crate log;
+
+use actix::prelude::*;
+use actix_web::middleware::identity::{CookieIdentityPolicy, IdentityService};
+use actix_web::middleware::session::{CookieSessionBackend, SessionStorage};
+use actix_web::{http::Method, middleware, server, App};
+use listenfd::ListenFd;
+use qs::{config::Config, db, graphql, oauth, queue, scheduler, webhooks, worker, AppState};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+fn main() {
+    dotenv::dotenv().ok();
+    std::env::set_var("RUST_LOG", "info");
+    env_logger::init();
+
+    let config = Config::from_env();
+
+    let sys = actix::System::new("qs");
+
+    let pool = db::init_pool(config.db_url.clone());
+    let graphql_pool = pool.clone();
+    let webhook_pool = pool.clone();
+    let worker_pool = pool.clone();
+
+    let shutdown = Arc::new(AtomicBool::new(false));
+
+    let db_keyring = config.token_keyring.clone();
+    let db_addr = SyncArbiter::start(3, move || db::DbExecutor(pool.clone(), db_keyring.clone()));
+
+    let schema = std::sync::Arc::new(graphql::schema::create_schema());
+    let redis_url = config.redis_url.clone();
+    let queue_name = config.queue_name.clone();
+    let graphql_oauth = Arc::new(config.oauth());
+    let graphql_keyring = config.token_keyring.clone();
+    let graphql_addr = SyncArbiter::start(2, move || {
+        graphql::GraphQLExecutor::new(
+            schema.clone(),
+            graphql_pool.clone(),
+            queue::init_queue(&redis_url, queue_name.clone()),
+            graphql_oauth.clone(),
+            graphql_keyring.clone(),
+        )
+    });
+
+    let oauth_config = config.clone();
+    let oauth_addr = SyncArbiter::start(2, move || oauth::OAuthExecutor(oauth_config.oauth()));
+
+    let webhook_config = config.clone();
+    let webhook_addr = SyncArbiter::start(2, move || {
+        webhooks::WebhookExecutor::new(
+            webhook_pool.clone(),
+            queue::init_queue(&webhook_config.redis_url, webhook_config.queue_name.clone()),
+            webhook_config.token_keyring.clone(),
+            &webhook_config.fitbit_secret,
+            webhook_config.fitbit_verification_code.clone(),
+        )
+    });
+
+    let cookie_key = config.cookie_key.clone();
+    let mut listenfd = ListenFd::from_env();
+    let mut server = server::new(move || {
+        App::with_state(AppState {
+            db: db_addr.clone(),
+            graphql: graphql_addr.clone(),
+            oauth: oauth_addr.clone(),
+            webhooks: webhook_addr.clone(),
+        })
+        .middleware(middleware::Logger::default())
+        // TODO secure: true on prod
+        .middleware(SessionStorage::new(
+            CookieSessionBackend::signed(cookie_key.as_bytes())
+                .secure(false)
+                .http_only(true),
+        ))
+        .middleware(IdentityService::new(
+            CookieIdentityPolicy::new(cookie_key.as_bytes())
+                .name("auth")
+                .secure(false),
+        ))
+        .resource("/oauth/{service}/start", |r| {
+            r.method(Method::GET).f(oauth::start_oauth)
+        })
+        .resource("/oauth/{service}/callback", |r| {
+            r.method(Method::GET).f(oauth::oauth_callback)
+        })
+        .resource("/webhooks/fitbit", |r| {
+            r.method(Method::GET).f(webhooks::fitbit_verify);
+            r.method(Method::POST).f(webhooks::fitbit_notification)
+        })
+        .resource("/graphql", |r| r.method(Method::POST).f(graphql::graphql))
+        .resource("/graphiql", |r| r.method(Method::GET).h(graphql::graphiql))
+        .resource("/logout", |r| r.method(Method::GET).f(oauth::logout))
+    });
+
+    server = if let Some(l) = listenfd.take_tcp_listener(0).unwrap() {
+        server.listen(l)
+    } else {
+        server.bind("127.0.0.1:8081").unwrap()
+    };
+
+    // in production jobs are processed by qs-worker
+    let threads = if config.env == "dev" {
+        let mut threads = worker::spawn_workers(&config, &worker_pool, &shutdown);
+        threads.push(scheduler::spawn_scheduler(&config, &worker_pool, &shutdown));
+        threads
+    } else {
+        vec![]
+    };
+
+    server.run();
+    sys.run();
+
+    shutdown.store(true, Ordering::SeqCst);
+    info!("Shutting down...");
+
+    for thread in threads {
+        let _ = thread.join();
+    }
+    info!("Worker threads shut down.");
+}
@@ -0,0 +1,124 @@
+#[macro_use]
+extern crate log;
+
+use qs::config::{Config, QueueConfig};
+use qs::{db, scheduler, worker};
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use uuid::Uuid;
+
+static USAGE: &str = "usage:
+  qs-worker                      run NUM_WORKERS workers and the scheduler until SIGTERM
+  qs-worker dlq list             list dead letters
+  qs-worker dlq show <id>        print a dead letter
+  qs-worker dlq requeue <id>     push a dead letter back onto the queue
+  qs-worker dlq purge [<id>]     delete one dead letter, or all of them
+  qs-worker encrypt-tokens       seal plaintext tokens, and tokens under old keys, with the current key";
+
+fn run_workers(config: &Config) {
+    let pool = db::init_pool(config.db_url.clone());
+
+    // workers check this between actions, so whatever is in flight when we get a signal finishes
+    let shutdown = Arc::new(AtomicBool::new(false));
+    for &signal in &[signal_hook::SIGTERM, signal_hook::SIGINT] {
+        signal_hook::flag::register(signal, shutdown.clone())
+            .expect("Failed to register signal handler");
+    }
+
+    let mut threads = worker::spawn_workers(config, &pool, &shutdown);
+    info!("Started {} workers", threads.len());
+    threads.push(scheduler::spawn_scheduler(config, &pool, &shutdown));
+
+    for thread in threads {
+        let _ = thread.join();
+    }
+    info!("Worker threads shut down.");
+}
+
+fn dlq(config: &QueueConfig, args: &[String]) -> Result<(), String> {
+    let retries = config.retry_queue();
+    let id = || {
+        args.get(1)
+            .ok_or_else(|| USAGE.to_string())?
+            .parse::<Uuid>()
+            .map_err(|e| e.to_string())
+    };
+
+    match args.first().map(String::as_str) {
+        Some("list") => {
+            for dead_letter in retries.dead_letters().map_err(|e| e.to_string())? {
+                println!(
+                    "{}\t{}\t{:?}\t{}",
+                    dead_letter.action.id,
+                    dead_letter.failed_at,
+                    dead_letter.action.params,
+                    dead_letter.error
+                );
+            }
+        }
+        Some("show") => match retries.dead_letter(&id()?).map_err(|e| e.to_string())? {
+            Some(dead_letter) => println!(
+                "{}",
+                serde_json::to_string_pretty(&dead_letter).map_err(|e| e.to_string())?
+            ),
+            None => return Err("no such dead letter".to_string()),
+        },
+        Some("requeue") => {
+            let queue = config.queue();
+            let pool = db::init_pool(config.db_url.clone());
+            let conn = pool.get().map_err(|e| e.to_string())?;
+            if !retries
+                .requeue(&conn, &id()?, &queue)
+                .map_err(|e| e.to_string())?
+            {
+                return Err("no such dead letter".to_string());
+            }
+        }
+        Some("purge") => {
+            let purged = match args.get(1) {
+                Some(_) => retries.purge(Some(&id()?)),
+                None => retries.purge(None),
+            }
+            .map_err(|e| e.to_string())?;
+            println!("Purged {} dead letters", purged);
+        }
+        _ => return Err(USAGE.to_string()),
+    }
+
+    Ok(())
+}
+
+fn encrypt_tokens(config: &Config) -> Result<(), String> {
+    let pool = db::init_pool(config.db_url.clone());
+    let conn = pool.get().map_err(|e| e.to_string())?;
+
+    let encrypted =
+        db::Token::encrypt_all(&conn, &config.token_keyring).map_err(|e| e.to_string())?;
+    println!("Encrypted {} tokens", encrypted);
+
+    Ok(())
+}
+
+fn main() {
+    dotenv::dotenv().ok();
+    std::env::set_var("RUST_LOG", "info");
+    env_logger::init();
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    // only running workers needs the OAuth secrets
+    let result = match args.first().map(String::as_str) {
+        None => {
+            run_workers(&Config::from_env());
+            Ok(())
+        }
+        Some("dlq") => dlq(&QueueConfig::from_env(), &args[1..]),
+        Some("encrypt-tokens") => encrypt_tokens(&Config::from_env()),
+        Some(_) => Err(USAGE.to_string()),
+    };
+
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
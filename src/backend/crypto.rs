@@ -0,0 +1,172 @@
+//! Envelope encryption for secrets we keep in the db. Each secret is sealed with its own random
+//! data key, and the data key is sealed with a master key from the `Keyring`. Rotating
+//! master keys only means re-sealing data keys, and the key id stored alongside says which master
+//! key to open one with.
+use ring::aead::{self, OpeningKey, SealingKey, AES_256_GCM};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+
+#[derive(Debug)]
+pub enum CryptoError {
+    UnknownKey(String),
+    Malformed,
+    Failed,
+}
+
+impl Display for CryptoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CryptoError::UnknownKey(id) => write!(f, "unknown encryption key {}", id),
+            CryptoError::Malformed => write!(f, "malformed ciphertext"),
+            CryptoError::Failed => write!(f, "encryption failed"),
+        }
+    }
+}
+
+impl std::error::Error for CryptoError {}
+
+/// A secret sealed under a fresh data key, along with that key sealed under a master key. All
+/// three are base64 so they fit in TEXT columns.
+#[derive(Debug, Clone)]
+pub struct Envelope {
+    pub key_id: String,
+    pub data_key: String,
+    pub ciphertexts: Vec<String>,
+}
+
+/// Master keys by id. The current one seals, the others only open what they sealed before.
+#[derive(Clone)]
+pub struct Keyring {
+    current: String,
+    keys: HashMap<String, Vec<u8>>,
+}
+
+/// Only the key ids, the keys themselves shouldn't end up in logs.
+impl fmt::Debug for Keyring {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Keyring")
+            .field("current", &self.current)
+            .field("key_ids", &self.keys.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+/// AES-256-GCM with a random nonce, returned as base64 of the nonce followed by the sealed data.
+/// `aad` ties the ciphertext to where it's stored, so it can't be moved to another row.
+fn seal(key: &[u8], plaintext: &[u8], aad: &str) -> Result<String, CryptoError> {
+    let key = SealingKey::new(&AES_256_GCM, key).map_err(|_| CryptoError::Failed)?;
+    let mut nonce = [0u8; NONCE_LEN];
+    SystemRandom::new()
+        .fill(&mut nonce)
+        .map_err(|_| CryptoError::Failed)?;
+
+    let mut in_out = plaintext.to_vec();
+    in_out.extend(vec![0u8; AES_256_GCM.tag_len()]);
+    let len = aead::seal_in_place(
+        &key,
+        &nonce,
+        aad.as_bytes(),
+        &mut in_out,
+        AES_256_GCM.tag_len(),
+    )
+    .map_err(|_| CryptoError::Failed)?;
+
+    let mut sealed = nonce.to_vec();
+    sealed.extend(&in_out[..len]);
+    Ok(base64::encode(&sealed))
+}
+
+fn open(key: &[u8], sealed: &str, aad: &str) -> Result<Vec<u8>, CryptoError> {
+    let key = OpeningKey::new(&AES_256_GCM, key).map_err(|_| CryptoError::Failed)?;
+    let mut sealed = base64::decode(sealed).map_err(|_| CryptoError::Malformed)?;
+    if sealed.len() < NONCE_LEN {
+        return Err(CryptoError::Malformed);
+    }
+
+    let (nonce, in_out) = sealed.split_at_mut(NONCE_LEN);
+    let plaintext = aead::open_in_place(&key, nonce, aad.as_bytes(), 0, in_out)
+        .map_err(|_| CryptoError::Malformed)?;
+    Ok(plaintext.to_vec())
+}
+
+impl Keyring {
+    /// Parses `<id>:<base64 key>,...`, where the first key is the current one.
+    pub fn parse(keys: &str) -> Result<Keyring, CryptoError> {
+        let mut current = None;
+        let mut parsed = HashMap::new();
+
+        for entry in keys.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let mut parts = entry.splitn(2, ':');
+            let (id, key) = match (parts.next(), parts.next()) {
+                (Some(id), Some(key)) => (id, key),
+                _ => return Err(CryptoError::Malformed),
+            };
+            let key = base64::decode(key).map_err(|_| CryptoError::Malformed)?;
+            if key.len() != KEY_LEN {
+                return Err(CryptoError::Malformed);
+            }
+
+            current.get_or_insert_with(|| id.to_string());
+            parsed.insert(id.to_string(), key);
+        }
+
+        Ok(Keyring {
+            current: current.ok_or(CryptoError::Malformed)?,
+            keys: parsed,
+        })
+    }
+
+    pub fn current_key_id(&self) -> &str {
+        &self.current
+    }
+
+    fn key(&self, key_id: &str) -> Result<&[u8], CryptoError> {
+        self.keys
+            .get(key_id)
+            .map(Vec::as_slice)
+            .ok_or_else(|| CryptoError::UnknownKey(key_id.to_string()))
+    }
+
+    /// Seals every secret under one new data key. Each secret is bound to its own `aad`, and the
+    /// data key to `key_aad`.
+    pub fn seal(&self, key_aad: &str, secrets: &[(&str, &str)]) -> Result<Envelope, CryptoError> {
+        let mut data_key = [0u8; KEY_LEN];
+        SystemRandom::new()
+            .fill(&mut data_key)
+            .map_err(|_| CryptoError::Failed)?;
+
+        let ciphertexts = secrets
+            .iter()
+            .map(|(secret, aad)| seal(&data_key, secret.as_bytes(), aad))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Envelope {
+            key_id: self.current.clone(),
+            data_key: seal(self.key(&self.current)?, &data_key, key_aad)?,
+            ciphertexts,
+        })
+    }
+
+    /// Opens secrets sealed by `seal`, given the same `aad`s in the same order.
+    pub fn open(
+        &self,
+        key_id: &str,
+        data_key: &str,
+        key_aad: &str,
+        secrets: &[(&str, &str)],
+    ) -> Result<Vec<String>, CryptoError> {
+        let data_key = open(self.key(key_id)?, data_key, key_aad)?;
+
+        secrets
+            .iter()
+            .map(|(sealed, aad)| {
+                let plaintext = open(&data_key, sealed, aad)?;
+                String::from_utf8(plaintext).map_err(|_| CryptoError::Malformed)
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,151 @@
+#![allow(proc_macro_derive_resolution_fallback)]
+
+use super::schema::access_tokens;
+use chrono::{DateTime, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use ring::rand::{SecureRandom, SystemRandom};
+use sha2::{Digest, Sha256};
+use std::str::FromStr;
+use uuid::Uuid;
+
+use crate::db::schema;
+
+/// Every token starts with this, so they're easy to spot in configs and logs.
+static TOKEN_PREFIX: &'static str = "qs_";
+const TOKEN_BYTES: usize = 32;
+/// How much of a token is kept in the clear, prefix included.
+const VISIBLE_CHARS: usize = 8;
+
+#[derive(GraphQLEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum AccessTokenScope {
+    Read,
+    ReadWrite,
+}
+
+impl AccessTokenScope {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AccessTokenScope::Read => "read",
+            AccessTokenScope::ReadWrite => "read_write",
+        }
+    }
+}
+
+impl FromStr for AccessTokenScope {
+    type Err = String;
+
+    fn from_str(scope: &str) -> Result<AccessTokenScope, String> {
+        match scope {
+            "read" => Ok(AccessTokenScope::Read),
+            "read_write" => Ok(AccessTokenScope::ReadWrite),
+            _ => Err(format!("unknown access token scope {}", scope)),
+        }
+    }
+}
+
+/// A personal access token. The token itself is only ever shown once, when it's created.
+#[derive(Debug, Clone, Serialize, Deserialize, Queryable, Insertable)]
+pub struct AccessToken {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub name: String,
+    pub prefix: String,
+    #[serde(skip_serializing)]
+    pub token_hash: String,
+    /// `AccessTokenScope::as_str`
+    pub scope: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+fn hash(token: &str) -> String {
+    base64::encode(&Sha256::digest(token.as_bytes()))
+}
+
+impl AccessToken {
+    pub fn scope(&self) -> Option<AccessTokenScope> {
+        self.scope.parse().ok()
+    }
+
+    /// Creates a token for the user. Returns it along with the token itself, which can't be
+    /// recovered afterwards.
+    pub fn create(
+        conn: &PgConnection,
+        the_user_id: &Uuid,
+        the_name: &str,
+        the_scope: AccessTokenScope,
+        the_expires_at: Option<DateTime<Utc>>,
+    ) -> Result<(AccessToken, String), diesel::result::Error> {
+        let mut bytes = [0u8; TOKEN_BYTES];
+        SystemRandom::new().fill(&mut bytes).map_err(|_| {
+            diesel::result::Error::SerializationError("couldn't generate a token".into())
+        })?;
+        let token = format!(
+            "{}{}",
+            TOKEN_PREFIX,
+            base64::encode_config(&bytes, base64::URL_SAFE_NO_PAD)
+        );
+
+        let access_token = diesel::insert_into(access_tokens::table)
+            .values(&AccessToken {
+                id: Uuid::new_v4(),
+                user_id: *the_user_id,
+                name: the_name.to_string(),
+                prefix: token[..VISIBLE_CHARS].to_string(),
+                token_hash: hash(&token),
+                scope: the_scope.as_str().to_string(),
+                created_at: Utc::now(),
+                expires_at: the_expires_at,
+                last_used_at: None,
+            })
+            .get_result::<AccessToken>(conn)?;
+
+        Ok((access_token, token))
+    }
+
+    /// Finds the unexpired token `token` is, and notes that it was used.
+    pub fn authenticate(
+        conn: &PgConnection,
+        token: &str,
+    ) -> Result<AccessToken, diesel::result::Error> {
+        use self::schema::access_tokens::dsl::*;
+
+        let access_token = access_tokens
+            .filter(token_hash.eq(hash(token)))
+            .filter(expires_at.is_null().or(expires_at.gt(Utc::now())))
+            .first::<AccessToken>(conn)?;
+
+        diesel::update(access_tokens.find(access_token.id))
+            .set(last_used_at.eq(Utc::now()))
+            .execute(conn)?;
+
+        Ok(access_token)
+    }
+
+    /// The user's tokens, expired ones included, newest first.
+    pub fn for_user(
+        conn: &PgConnection,
+        the_user_id: &Uuid,
+    ) -> Result<Vec<AccessToken>, diesel::result::Error> {
+        use self::schema::access_tokens::dsl::*;
+
+        Ok(access_tokens
+            .filter(user_id.eq(the_user_id))
+            .order(created_at.desc())
+            .load::<AccessToken>(conn)?)
+    }
+
+    /// Revokes one of the user's tokens. Returns how many were deleted, 0 if it isn't theirs.
+    pub fn delete(
+        conn: &PgConnection,
+        the_user_id: &Uuid,
+        the_id: &Uuid,
+    ) -> Result<usize, diesel::result::Error> {
+        use self::schema::access_tokens::dsl::*;
+
+        diesel::delete(access_tokens.filter(id.eq(the_id).and(user_id.eq(the_user_id))))
+            .execute(conn)
+    }
+}
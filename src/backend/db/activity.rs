@@ -0,0 +1,199 @@
+#![allow(proc_macro_derive_resolution_fallback)]
+
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+use crate::providers::fitbit;
+use actix_web::{error, Error};
+
+measurement! {
+    #[graphql(description = "A single step datapoint")]
+    Step => steps { count: i32 }
+}
+
+measurement! {
+    #[graphql(description = "A single floor datapoint")]
+    Floor => floors { count: i32 }
+}
+
+measurement! {
+    #[graphql(description = "A single distance datapoint")]
+    Distance => distances { count: f64 }
+}
+
+measurement! {
+    #[graphql(description = "A single elevation datapoint")]
+    Elevation => elevations { count: f64 }
+}
+
+measurement! {
+    #[graphql(description = "A single calorie datapoint")]
+    Calorie => calories { count: f64, level: i32, mets: i32 }
+}
+
+impl fitbit::IntradayMeasurement for Step {
+    fn new(
+        user_id: Uuid,
+        time: DateTime<Utc>,
+        measurement: fitbit::IntradayValue,
+    ) -> Result<Self, Error> {
+        match measurement {
+            fitbit::IntradayValue::Integral(count) => Ok(Step {
+                user_id,
+                count: count.value,
+                source: "fitbit".to_string(),
+                time,
+            }),
+            _ => Err(error::ErrorInternalServerError("Wrong type!")),
+        }
+    }
+
+    fn name() -> &'static str {
+        "steps"
+    }
+
+    fn parse_response(r: fitbit::IntradayResponse) -> Option<Vec<fitbit::IntradayValue>> {
+        r.activities_steps_intraday.and_then(|a| {
+            Some(
+                a.dataset
+                    .into_iter()
+                    .map(fitbit::IntradayValue::Integral)
+                    .collect(),
+            )
+        })
+    }
+}
+
+impl fitbit::IntradayMeasurement for Floor {
+    fn new(
+        user_id: Uuid,
+        time: DateTime<Utc>,
+        measurement: fitbit::IntradayValue,
+    ) -> Result<Self, Error> {
+        match measurement {
+            fitbit::IntradayValue::Integral(count) => Ok(Floor {
+                user_id,
+                count: count.value,
+                source: "fitbit".to_string(),
+                time,
+            }),
+            _ => Err(error::ErrorInternalServerError("Wrong type!")),
+        }
+    }
+
+    fn name() -> &'static str {
+        "floors"
+    }
+
+    fn parse_response(r: fitbit::IntradayResponse) -> Option<Vec<fitbit::IntradayValue>> {
+        r.activities_floors_intraday.and_then(|a| {
+            Some(
+                a.dataset
+                    .into_iter()
+                    .map(fitbit::IntradayValue::Integral)
+                    .collect(),
+            )
+        })
+    }
+}
+
+impl fitbit::IntradayMeasurement for Distance {
+    fn new(
+        user_id: Uuid,
+        time: DateTime<Utc>,
+        measurement: fitbit::IntradayValue,
+    ) -> Result<Self, Error> {
+        match measurement {
+            fitbit::IntradayValue::Float(count) => Ok(Distance {
+                user_id,
+                count: count.value,
+                source: "fitbit".to_string(),
+                time,
+            }),
+            _ => Err(error::ErrorInternalServerError("Wrong type!")),
+        }
+    }
+
+    fn name() -> &'static str {
+        "distance"
+    }
+
+    fn parse_response(r: fitbit::IntradayResponse) -> Option<Vec<fitbit::IntradayValue>> {
+        r.activities_distance_intraday.and_then(|a| {
+            Some(
+                a.dataset
+                    .into_iter()
+                    .map(fitbit::IntradayValue::Float)
+                    .collect(),
+            )
+        })
+    }
+}
+
+impl fitbit::IntradayMeasurement for Elevation {
+    fn new(
+        user_id: Uuid,
+        time: DateTime<Utc>,
+        measurement: fitbit::IntradayValue,
+    ) -> Result<Self, Error> {
+        match measurement {
+            fitbit::IntradayValue::Float(count) => Ok(Elevation {
+                user_id,
+                count: count.value,
+                source: "fitbit".to_string(),
+                time,
+            }),
+            _ => Err(error::ErrorInternalServerError("Wrong type!")),
+        }
+    }
+
+    fn name() -> &'static str {
+        "elevation"
+    }
+
+    fn parse_response(r: fitbit::IntradayResponse) -> Option<Vec<fitbit::IntradayValue>> {
+        r.activities_elevation_intraday.and_then(|a| {
+            Some(
+                a.dataset
+                    .into_iter()
+                    .map(fitbit::IntradayValue::Float)
+                    .collect(),
+            )
+        })
+    }
+}
+
+impl fitbit::IntradayMeasurement for Calorie {
+    fn new(
+        user_id: Uuid,
+        time: DateTime<Utc>,
+        measurement: fitbit::IntradayValue,
+    ) -> Result<Self, Error> {
+        match measurement {
+            fitbit::IntradayValue::Caloric(count) => Ok(Calorie {
+                user_id,
+                count: count.value,
+                source: "fitbit".to_string(),
+                time,
+                level: count.level,
+                mets: count.mets,
+            }),
+            _ => Err(error::ErrorInternalServerError("Wrong type!")),
+        }
+    }
+
+    fn name() -> &'static str {
+        "calories"
+    }
+
+    fn parse_response(r: fitbit::IntradayResponse) -> Option<Vec<fitbit::IntradayValue>> {
+        r.activities_calories_intraday.and_then(|a| {
+            Some(
+                a.dataset
+                    .into_iter()
+                    .map(fitbit::IntradayValue::Caloric)
+                    .collect(),
+            )
+        })
+    }
+}
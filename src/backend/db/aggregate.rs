@@ -0,0 +1,214 @@
+#![allow(proc_macro_derive_resolution_fallback)]
+
+use chrono::{DateTime, Duration, Offset, TimeZone, Utc};
+use chrono_tz::Tz;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::sql_types::{Double, Text, Timestamptz, Uuid as SqlUuid};
+use std::str::FromStr;
+use uuid::Uuid;
+
+#[derive(GraphQLEnum, Debug, Clone, Copy, PartialEq)]
+pub enum AggregateFunction {
+    Sum,
+    Avg,
+    Min,
+    Max,
+}
+
+impl AggregateFunction {
+    fn as_sql(self, source: Source) -> &'static str {
+        match (source, self) {
+            (Source::Raw, AggregateFunction::Sum) => "SUM(count)",
+            (Source::Raw, AggregateFunction::Avg) => "AVG(count)",
+            (Source::Raw, AggregateFunction::Min) => "MIN(count)",
+            (Source::Raw, AggregateFunction::Max) => "MAX(count)",
+            (_, AggregateFunction::Sum) => "SUM(total)",
+            (_, AggregateFunction::Avg) => "SUM(total)::DOUBLE PRECISION / SUM(samples)",
+            (_, AggregateFunction::Min) => "MIN(minimum)",
+            (_, AggregateFunction::Max) => "MAX(maximum)",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Bucket {
+    Hour,
+    Day,
+    Week,
+}
+
+impl Bucket {
+    fn interval(self) -> &'static str {
+        match self {
+            Bucket::Hour => "1 hour",
+            Bucket::Day => "1 day",
+            Bucket::Week => "1 week",
+        }
+    }
+}
+
+impl FromStr for Bucket {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Bucket, String> {
+        match s {
+            "1h" => Ok(Bucket::Hour),
+            "1d" => Ok(Bucket::Day),
+            "1w" => Ok(Bucket::Week),
+            _ => Err(format!("unknown bucket {}, expected 1h, 1d or 1w", s)),
+        }
+    }
+}
+
+/// Ranges at least this long are served from the `_hourly`/`_daily` continuous aggregates.
+pub const ROLLUP_MIN_DAYS: i64 = 7;
+
+/// Where an aggregate query reads from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Source {
+    Raw,
+    Hourly,
+    Daily,
+}
+
+impl Source {
+    fn for_query(tz: Tz, (start, end): (&DateTime<Utc>, &DateTime<Utc>), bucket: Bucket) -> Source {
+        if *end - *start < Duration::days(ROLLUP_MIN_DAYS) {
+            return Source::Raw;
+        }
+
+        let is_utc = match tz {
+            Tz::UTC | Tz::Etc__UTC | Tz::GMT | Tz::Etc__GMT => true,
+            _ => false,
+        };
+        let on_multiple_of = |time: &DateTime<Utc>, secs: i64| {
+            time.timestamp() % secs == 0 && time.timestamp_subsec_nanos() == 0
+        };
+        let whole_hour_offset = |time: &DateTime<Utc>| {
+            tz.offset_from_utc_datetime(&time.naive_utc())
+                .fix()
+                .local_minus_utc()
+                % 3600
+                == 0
+        };
+
+        // rollups are only exact when the range doesn't cut through one of their buckets
+        if is_utc
+            && bucket != Bucket::Hour
+            && on_multiple_of(start, 86400)
+            && on_multiple_of(end, 86400)
+        {
+            Source::Daily
+        } else if whole_hour_offset(start)
+            && whole_hour_offset(end)
+            && on_multiple_of(start, 3600)
+            && on_multiple_of(end, 3600)
+        {
+            Source::Hourly
+        } else {
+            Source::Raw
+        }
+    }
+
+    fn relation(self, table: &str) -> String {
+        match self {
+            Source::Raw => table.to_string(),
+            Source::Hourly => format!("{}_hourly", table),
+            Source::Daily => format!("{}_daily", table),
+        }
+    }
+
+    fn time_column(self) -> &'static str {
+        match self {
+            Source::Raw => "time",
+            _ => "bucket",
+        }
+    }
+}
+
+#[derive(GraphQLObject, QueryableByName, Debug, Clone, Serialize, Deserialize)]
+#[graphql(description = "A measurement aggregated over one time bucket")]
+pub struct AggregatePoint {
+    /// Start of the bucket
+    #[sql_type = "Timestamptz"]
+    pub time: DateTime<Utc>,
+    #[sql_type = "Double"]
+    pub value: f64,
+}
+
+/// Aggregates `table`'s `count` column into buckets with `time_bucket`. Buckets are cut on the
+/// wall clock of `tz`, so a day runs from local midnight to local midnight (even across DST) and
+/// weeks start on Monday. Empty buckets are left out. Long ranges are read from the rollups when
+/// that gives the same answer.
+pub fn aggregate(
+    conn: &PgConnection,
+    table: &str,
+    user_id: &Uuid,
+    (start, end): (&DateTime<Utc>, &DateTime<Utc>),
+    bucket: Bucket,
+    function: AggregateFunction,
+    tz: Tz,
+) -> Result<Vec<AggregatePoint>, diesel::result::Error> {
+    let source = Source::for_query(tz, (start, end), bucket);
+    let time = source.time_column();
+
+    // table and function come from our own enums, everything else is bound
+    let query = format!(
+        "SELECT time_bucket($1::interval, {time} AT TIME ZONE $2) AT TIME ZONE $2 AS time, \
+         ({value})::DOUBLE PRECISION AS value \
+         FROM {relation} \
+         WHERE user_id = $3 AND {time} >= $4 AND {time} < $5 \
+         GROUP BY 1 ORDER BY 1",
+        time = time,
+        value = function.as_sql(source),
+        relation = source.relation(table),
+    );
+
+    diesel::sql_query(query)
+        .bind::<Text, _>(bucket.interval())
+        .bind::<Text, _>(tz.name())
+        .bind::<SqlUuid, _>(user_id)
+        .bind::<Timestamptz, _>(start)
+        .bind::<Timestamptz, _>(end)
+        .load::<AggregatePoint>(conn)
+}
+
+/// Materializes the rollups of `table` between `start` and `end`. The refresh policies only look
+/// at the last few days, so anything ingested for older days has to be refreshed explicitly.
+/// Can't be called inside a transaction.
+pub fn refresh_rollups(
+    conn: &PgConnection,
+    table: &str,
+    start: &DateTime<Utc>,
+    end: &DateTime<Utc>,
+) -> Result<(), diesel::result::Error> {
+    // a refresh only covers buckets that fit entirely in the window, so widen it to whole days
+    let start = start.date().and_hms(0, 0, 0);
+    let end = (end.date() + Duration::days(1)).and_hms(0, 0, 0);
+
+    for source in &[Source::Hourly, Source::Daily] {
+        diesel::sql_query(format!(
+            "CALL refresh_continuous_aggregate('{}', $1, $2)",
+            source.relation(table)
+        ))
+        .bind::<Timestamptz, _>(&start)
+        .bind::<Timestamptz, _>(&end)
+        .execute(conn)?;
+    }
+
+    Ok(())
+}
+
+/// Materializes the rollups of `table` over all time, after data was deleted from anywhere in it.
+pub fn refresh_all_rollups(conn: &PgConnection, table: &str) -> Result<(), diesel::result::Error> {
+    for source in &[Source::Hourly, Source::Daily] {
+        diesel::sql_query(format!(
+            "CALL refresh_continuous_aggregate('{}', NULL, NULL)",
+            source.relation(table)
+        ))
+        .execute(conn)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,158 @@
+#![allow(proc_macro_derive_resolution_fallback)]
+
+use super::schema::body_measurements;
+use chrono::{DateTime, Utc};
+use diesel::pg::upsert::excluded;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use uuid::Uuid;
+
+use crate::db::{
+    schema, was_inserted, DbExecutor, Handler, Message, Object, Upsert, UpsertStats,
+    INSERT_CHUNK_SIZE,
+};
+use crate::error::AppError;
+
+#[derive(GraphQLEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum BodyMeasurementKind {
+    // kg
+    Weight,
+    Bmi,
+    // percent
+    Fat,
+}
+
+impl BodyMeasurementKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            BodyMeasurementKind::Weight => "weight",
+            BodyMeasurementKind::Bmi => "bmi",
+            BodyMeasurementKind::Fat => "fat",
+        }
+    }
+}
+
+#[derive(GraphQLObject, Debug, Clone, Serialize, Deserialize, Queryable, Insertable)]
+#[graphql(description = "A single body measurement (weight in kg, BMI or body fat %)")]
+pub struct BodyMeasurement {
+    pub time: DateTime<Utc>,
+    pub user_id: Uuid,
+    pub kind: String,
+    pub source: String,
+    pub value: f64,
+}
+
+impl BodyMeasurement {
+    pub fn new(
+        user_id: Uuid,
+        time: DateTime<Utc>,
+        kind: BodyMeasurementKind,
+        value: f64,
+    ) -> BodyMeasurement {
+        BodyMeasurement {
+            time,
+            user_id,
+            kind: kind.as_str().to_string(),
+            source: "fitbit".to_string(),
+            value,
+        }
+    }
+
+    pub fn for_period(
+        conn: &PgConnection,
+        the_user_id: &Uuid,
+        the_kind: BodyMeasurementKind,
+        start: &DateTime<Utc>,
+        end: &DateTime<Utc>,
+    ) -> Result<Vec<BodyMeasurement>, diesel::result::Error> {
+        use self::schema::body_measurements::dsl::*;
+
+        Ok(body_measurements
+            .filter(
+                user_id
+                    .eq(the_user_id)
+                    .and(kind.eq(the_kind.as_str()))
+                    .and(time.ge(start).and(time.lt(end))),
+            )
+            .order(time.desc())
+            .load::<BodyMeasurement>(conn)?)
+    }
+
+    pub fn find_one(
+        conn: &PgConnection,
+        (user_id, time, kind): (&Uuid, &DateTime<Utc>, &str),
+    ) -> Result<BodyMeasurement, diesel::result::Error> {
+        Ok(body_measurements::table
+            .find((user_id, time, kind))
+            .get_result::<BodyMeasurement>(conn)?)
+    }
+}
+
+impl Object for BodyMeasurement {
+    fn insert(
+        conn: &PgConnection,
+        measurement: &BodyMeasurement,
+    ) -> Result<BodyMeasurement, diesel::result::Error> {
+        use self::schema::body_measurements::dsl::*;
+
+        diesel::insert_into(body_measurements)
+            .values(measurement)
+            .execute(conn)?;
+
+        Ok(BodyMeasurement::find_one(
+            conn,
+            (
+                &measurement.user_id,
+                &measurement.time,
+                measurement.kind.as_str(),
+            ),
+        )?)
+    }
+
+    fn insert_many(
+        conn: &PgConnection,
+        measurements: &[BodyMeasurement],
+    ) -> Result<usize, diesel::result::Error> {
+        use self::schema::body_measurements::dsl::*;
+
+        diesel::insert_into(body_measurements)
+            .values(measurements)
+            .execute(conn)
+    }
+}
+
+impl Upsert for BodyMeasurement {
+    fn upsert_many(
+        conn: &PgConnection,
+        measurements: &[BodyMeasurement],
+    ) -> Result<UpsertStats, diesel::result::Error> {
+        use self::schema::body_measurements::dsl::*;
+
+        let mut stats = UpsertStats::default();
+        for chunk in measurements.chunks(INSERT_CHUNK_SIZE) {
+            let inserted = diesel::insert_into(body_measurements)
+                .values(chunk)
+                .on_conflict((user_id, time, kind))
+                .do_update()
+                .set((source.eq(excluded(source)), value.eq(excluded(value))))
+                .returning(was_inserted())
+                .get_results::<bool>(conn)?;
+            stats.record(&inserted);
+        }
+
+        Ok(stats)
+    }
+}
+
+impl Message for BodyMeasurement {
+    type Result = Result<BodyMeasurement, AppError>;
+}
+
+impl Handler<BodyMeasurement> for DbExecutor {
+    type Result = Result<BodyMeasurement, AppError>;
+
+    fn handle(&mut self, msg: BodyMeasurement, _: &mut Self::Context) -> Self::Result {
+        let conn = &self.0.get()?;
+        Ok(BodyMeasurement::insert(conn, &msg)?)
+    }
+}
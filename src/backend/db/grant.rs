@@ -0,0 +1,139 @@
+#![allow(proc_macro_derive_resolution_fallback)]
+
+use super::schema::grants;
+use chrono::{DateTime, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use std::str::FromStr;
+use uuid::Uuid;
+
+use crate::db::schema;
+
+/// What a grant can share. Heart rate covers resting heart rates and zones too.
+#[derive(GraphQLEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum GrantMetric {
+    Step,
+    Calorie,
+    Distance,
+    Elevation,
+    Floor,
+    HeartRate,
+    Sleep,
+    Weight,
+    BodyFat,
+    Mood,
+}
+
+impl GrantMetric {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            GrantMetric::Step => "step",
+            GrantMetric::Calorie => "calorie",
+            GrantMetric::Distance => "distance",
+            GrantMetric::Elevation => "elevation",
+            GrantMetric::Floor => "floor",
+            GrantMetric::HeartRate => "heart_rate",
+            GrantMetric::Sleep => "sleep",
+            GrantMetric::Weight => "weight",
+            GrantMetric::BodyFat => "body_fat",
+            GrantMetric::Mood => "mood",
+        }
+    }
+}
+
+impl FromStr for GrantMetric {
+    type Err = String;
+
+    fn from_str(metric: &str) -> Result<GrantMetric, String> {
+        match metric {
+            "step" => Ok(GrantMetric::Step),
+            "calorie" => Ok(GrantMetric::Calorie),
+            "distance" => Ok(GrantMetric::Distance),
+            "elevation" => Ok(GrantMetric::Elevation),
+            "floor" => Ok(GrantMetric::Floor),
+            "heart_rate" => Ok(GrantMetric::HeartRate),
+            "sleep" => Ok(GrantMetric::Sleep),
+            "weight" => Ok(GrantMetric::Weight),
+            "body_fat" => Ok(GrantMetric::BodyFat),
+            "mood" => Ok(GrantMetric::Mood),
+            _ => Err(format!("unknown grant metric {}", metric)),
+        }
+    }
+}
+
+/// Read-only access to some of the owner's metrics for the grantee, between `start_time` and
+/// `end_time` (unbounded if `None`), until `expires_at` (never if `None`).
+#[derive(Debug, Clone, Serialize, Deserialize, Queryable, Insertable)]
+pub struct Grant {
+    pub id: Uuid,
+    pub owner_id: Uuid,
+    pub grantee_id: Uuid,
+    /// `GrantMetric::as_str` of every metric shared
+    pub metrics: Vec<String>,
+    pub start_time: Option<DateTime<Utc>>,
+    pub end_time: Option<DateTime<Utc>>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Grant {
+    pub fn includes(&self, metric: GrantMetric) -> bool {
+        self.metrics.iter().any(|m| m == metric.as_str())
+    }
+
+    pub fn insert(conn: &PgConnection, grant: &Grant) -> Result<Grant, diesel::result::Error> {
+        diesel::insert_into(grants::table)
+            .values(grant)
+            .get_result::<Grant>(conn)
+    }
+
+    pub fn find_one(conn: &PgConnection, id: &Uuid) -> Result<Grant, diesel::result::Error> {
+        Ok(grants::table.find(id).get_result::<Grant>(conn)?)
+    }
+
+    /// Grants from `the_owner_id` to `the_grantee_id` that haven't expired.
+    pub fn active_between(
+        conn: &PgConnection,
+        the_owner_id: &Uuid,
+        the_grantee_id: &Uuid,
+    ) -> Result<Vec<Grant>, diesel::result::Error> {
+        use self::schema::grants::dsl::*;
+
+        Ok(grants
+            .filter(owner_id.eq(the_owner_id))
+            .filter(grantee_id.eq(the_grantee_id))
+            .filter(expires_at.is_null().or(expires_at.gt(Utc::now())))
+            .load::<Grant>(conn)?)
+    }
+
+    /// Grants to `the_grantee_id` that haven't expired, newest first.
+    pub fn active_for_grantee(
+        conn: &PgConnection,
+        the_grantee_id: &Uuid,
+    ) -> Result<Vec<Grant>, diesel::result::Error> {
+        use self::schema::grants::dsl::*;
+
+        Ok(grants
+            .filter(grantee_id.eq(the_grantee_id))
+            .filter(expires_at.is_null().or(expires_at.gt(Utc::now())))
+            .order(created_at.desc())
+            .load::<Grant>(conn)?)
+    }
+
+    /// Every grant `the_owner_id` has made, expired ones included, newest first.
+    pub fn for_owner(
+        conn: &PgConnection,
+        the_owner_id: &Uuid,
+    ) -> Result<Vec<Grant>, diesel::result::Error> {
+        use self::schema::grants::dsl::*;
+
+        Ok(grants
+            .filter(owner_id.eq(the_owner_id))
+            .order(created_at.desc())
+            .load::<Grant>(conn)?)
+    }
+
+    pub fn delete(conn: &PgConnection, id: &Uuid) -> Result<usize, diesel::result::Error> {
+        diesel::delete(grants::table.find(id)).execute(conn)
+    }
+}
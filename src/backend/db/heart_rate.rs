@@ -0,0 +1,195 @@
+#![allow(proc_macro_derive_resolution_fallback)]
+
+use super::schema::heart_rate_zones;
+use chrono::{DateTime, Utc};
+use diesel::pg::upsert::excluded;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use uuid::Uuid;
+
+use crate::db::{schema, was_inserted, Object, Upsert, UpsertStats, INSERT_CHUNK_SIZE};
+use crate::providers::fitbit;
+use actix_web::{error, Error};
+
+measurement! {
+    #[graphql(description = "A single heart rate datapoint, in beats per minute")]
+    HeartRate => heart_rates { count: i32 }
+}
+
+measurement! {
+    #[graphql(description = "A heart rate averaged over a minute, in beats per minute")]
+    HeartRateMinute => heart_rate_minutes { count: i32 }
+}
+
+measurement! {
+    #[graphql(description = "A daily resting heart rate, in beats per minute")]
+    RestingHeartRate => resting_heart_rates { count: i32 }
+}
+
+#[derive(GraphQLObject, Debug, Clone, Serialize, Deserialize, Queryable, Insertable)]
+#[graphql(description = "Time spent in a heart rate zone over a day")]
+pub struct HeartRateZone {
+    pub time: DateTime<Utc>,
+    pub user_id: Uuid,
+    pub source: String,
+    pub name: String,
+    pub min: i32,
+    pub max: i32,
+    pub minutes: i32,
+    pub calories_out: f64,
+}
+
+/// Both resolutions come back in the same `activities-heart-intraday` dataset.
+fn parse_heart_response(r: fitbit::IntradayResponse) -> Option<Vec<fitbit::IntradayValue>> {
+    r.activities_heart_intraday.and_then(|a| {
+        Some(
+            a.dataset
+                .into_iter()
+                .map(fitbit::IntradayValue::Integral)
+                .collect(),
+        )
+    })
+}
+
+impl fitbit::IntradayMeasurement for HeartRate {
+    fn new(
+        user_id: Uuid,
+        time: DateTime<Utc>,
+        measurement: fitbit::IntradayValue,
+    ) -> Result<Self, Error> {
+        match measurement {
+            fitbit::IntradayValue::Integral(count) => Ok(HeartRate {
+                user_id,
+                count: count.value,
+                source: "fitbit".to_string(),
+                time,
+            }),
+            _ => Err(error::ErrorInternalServerError("Wrong type!")),
+        }
+    }
+
+    fn name() -> &'static str {
+        "heart"
+    }
+
+    fn detail_level() -> &'static str {
+        "1sec"
+    }
+
+    fn parse_response(r: fitbit::IntradayResponse) -> Option<Vec<fitbit::IntradayValue>> {
+        parse_heart_response(r)
+    }
+}
+
+impl fitbit::IntradayMeasurement for HeartRateMinute {
+    fn new(
+        user_id: Uuid,
+        time: DateTime<Utc>,
+        measurement: fitbit::IntradayValue,
+    ) -> Result<Self, Error> {
+        match measurement {
+            fitbit::IntradayValue::Integral(count) => Ok(HeartRateMinute {
+                user_id,
+                count: count.value,
+                source: "fitbit".to_string(),
+                time,
+            }),
+            _ => Err(error::ErrorInternalServerError("Wrong type!")),
+        }
+    }
+
+    fn name() -> &'static str {
+        "heart"
+    }
+
+    fn parse_response(r: fitbit::IntradayResponse) -> Option<Vec<fitbit::IntradayValue>> {
+        parse_heart_response(r)
+    }
+}
+
+impl HeartRateZone {
+    pub fn for_period(
+        conn: &PgConnection,
+        the_user_id: &Uuid,
+        start: &DateTime<Utc>,
+        end: &DateTime<Utc>,
+    ) -> Result<Vec<HeartRateZone>, diesel::result::Error> {
+        use self::schema::heart_rate_zones::dsl::*;
+
+        Ok(heart_rate_zones
+            .filter(
+                user_id
+                    .eq(the_user_id)
+                    .and(time.ge(start).and(time.lt(end))),
+            )
+            .order((time.desc(), min.asc()))
+            .load::<HeartRateZone>(conn)?)
+    }
+
+    pub fn find_one(
+        conn: &PgConnection,
+        (user_id, time, name): (&Uuid, &DateTime<Utc>, &str),
+    ) -> Result<HeartRateZone, diesel::result::Error> {
+        Ok(heart_rate_zones::table
+            .find((user_id, time, name))
+            .get_result::<HeartRateZone>(conn)?)
+    }
+}
+
+impl Object for HeartRateZone {
+    fn insert(
+        conn: &PgConnection,
+        zone: &HeartRateZone,
+    ) -> Result<HeartRateZone, diesel::result::Error> {
+        use self::schema::heart_rate_zones::dsl::*;
+
+        diesel::insert_into(heart_rate_zones)
+            .values(zone)
+            .execute(conn)?;
+
+        Ok(HeartRateZone::find_one(
+            conn,
+            (&zone.user_id, &zone.time, zone.name.as_str()),
+        )?)
+    }
+
+    fn insert_many(
+        conn: &PgConnection,
+        zones: &[HeartRateZone],
+    ) -> Result<usize, diesel::result::Error> {
+        use self::schema::heart_rate_zones::dsl::*;
+
+        diesel::insert_into(heart_rate_zones)
+            .values(zones)
+            .execute(conn)
+    }
+}
+
+impl Upsert for HeartRateZone {
+    fn upsert_many(
+        conn: &PgConnection,
+        zones: &[HeartRateZone],
+    ) -> Result<UpsertStats, diesel::result::Error> {
+        use self::schema::heart_rate_zones::dsl::*;
+
+        let mut stats = UpsertStats::default();
+        for chunk in zones.chunks(INSERT_CHUNK_SIZE) {
+            let inserted = diesel::insert_into(heart_rate_zones)
+                .values(chunk)
+                .on_conflict((user_id, time, name))
+                .do_update()
+                .set((
+                    source.eq(excluded(source)),
+                    min.eq(excluded(min)),
+                    max.eq(excluded(max)),
+                    minutes.eq(excluded(minutes)),
+                    calories_out.eq(excluded(calories_out)),
+                ))
+                .returning(was_inserted())
+                .get_results::<bool>(conn)?;
+            stats.record(&inserted);
+        }
+
+        Ok(stats)
+    }
+}
@@ -0,0 +1,151 @@
+#![allow(proc_macro_derive_resolution_fallback)]
+
+use super::schema::identities;
+use super::user::{NewUser, User};
+use chrono::{DateTime, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use uuid::Uuid;
+
+use crate::db::{schema, DbExecutor, Handler, Message};
+use crate::error::AppError;
+
+/// An account at a provider (google, github) that logs in as a user. A user can have several.
+#[derive(Debug, Clone, Serialize, Deserialize, Queryable, Insertable)]
+#[table_name = "identities"]
+pub struct Identity {
+    pub provider: String,
+    /// The provider's id for the account
+    pub subject: String,
+    pub user_id: Uuid,
+    pub email: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Identity {
+    pub fn find_one(
+        conn: &PgConnection,
+        (provider, subject): (&str, &str),
+    ) -> Result<Identity, diesel::result::Error> {
+        Ok(identities::table
+            .find((provider, subject))
+            .get_result::<Identity>(conn)?)
+    }
+
+    pub fn for_user(
+        conn: &PgConnection,
+        the_user_id: &Uuid,
+    ) -> Result<Vec<Identity>, diesel::result::Error> {
+        use self::schema::identities::dsl::*;
+
+        Ok(identities
+            .filter(user_id.eq(the_user_id))
+            .order(created_at.asc())
+            .load::<Identity>(conn)?)
+    }
+
+    pub fn insert(
+        conn: &PgConnection,
+        identity: &Identity,
+    ) -> Result<Identity, diesel::result::Error> {
+        diesel::insert_into(identities::table)
+            .values(identity)
+            .get_result::<Identity>(conn)
+    }
+
+    /// Unlinks one of the user's identities. Returns how many were deleted, 0 if it isn't theirs.
+    pub fn delete(
+        conn: &PgConnection,
+        the_user_id: &Uuid,
+        (the_provider, the_subject): (&str, &str),
+    ) -> Result<usize, diesel::result::Error> {
+        use self::schema::identities::dsl::*;
+
+        diesel::delete(
+            identities.filter(
+                user_id
+                    .eq(the_user_id)
+                    .and(provider.eq(the_provider))
+                    .and(subject.eq(the_subject)),
+            ),
+        )
+        .execute(conn)
+    }
+}
+
+/// Someone came back from a provider that identifies them. Logs them in as the user the identity
+/// belongs to, or links it to `user_id` if they're already logged in, or signs them up.
+pub struct Login {
+    pub provider: String,
+    pub subject: String,
+    pub email: Option<String>,
+    /// Who they're logged in as already
+    pub user_id: Option<Uuid>,
+}
+
+impl Message for Login {
+    type Result = Result<User, AppError>;
+}
+
+impl Handler<Login> for DbExecutor {
+    type Result = Result<User, AppError>;
+
+    fn handle(&mut self, msg: Login, _: &mut Self::Context) -> Self::Result {
+        let conn: &PgConnection = &self.0.get()?;
+
+        let existing = Identity::find_one(conn, (&msg.provider, &msg.subject)).optional()?;
+        let user_id = match (existing, msg.user_id) {
+            (Some(identity), Some(user_id)) if identity.user_id != user_id => {
+                return Err(AppError::Conflict(format!(
+                    "This {} account is linked to someone else",
+                    msg.provider
+                )));
+            }
+            (Some(identity), _) => identity.user_id,
+            (None, user_id) => {
+                let email = msg.email.clone().ok_or_else(|| {
+                    AppError::BadRequest(format!("{} didn't share an email", msg.provider))
+                })?;
+
+                // an email alone doesn't prove it's the same person, they have to log in with
+                // the account they already have and link this one from there
+                if user_id.is_none() && User::find_one_by_email(conn, &email).is_ok() {
+                    return Err(AppError::Conflict(format!(
+                        "{} already has an account, log in to link {} to it",
+                        email, msg.provider
+                    )));
+                }
+
+                conn.transaction::<_, diesel::result::Error, _>(|| {
+                    let user_id = match user_id {
+                        Some(user_id) => user_id,
+                        None => {
+                            let id = Uuid::new_v4();
+                            User::insert(
+                                conn,
+                                &NewUser {
+                                    id: &id,
+                                    email: &email,
+                                },
+                            )?
+                            .id
+                        }
+                    };
+                    Identity::insert(
+                        conn,
+                        &Identity {
+                            provider: msg.provider.clone(),
+                            subject: msg.subject.clone(),
+                            user_id,
+                            email: Some(email.clone()),
+                            created_at: Utc::now(),
+                        },
+                    )?;
+                    Ok(user_id)
+                })?
+            }
+        };
+
+        Ok(User::find_one(conn, &user_id)?)
+    }
+}
@@ -0,0 +1,383 @@
+#![allow(proc_macro_derive_resolution_fallback)]
+
+use super::schema::jobs;
+use chrono::{DateTime, NaiveDate, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use std::str::FromStr;
+use uuid::Uuid;
+
+use crate::db::schema;
+use crate::error::AppError;
+use crate::providers::fitbit::IntradayMetric;
+use crate::queue::{Queue, QueueAction, QueueActionParams};
+
+#[derive(GraphQLEnum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum JobStatus {
+    Queued,
+    Running,
+    // waiting for a rate limit to reset
+    Deferred,
+    // failed, but will be tried again
+    Retrying,
+    Succeeded,
+    Failed,
+    // the service was disconnected before it ran
+    Cancelled,
+}
+
+impl JobStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            JobStatus::Queued => "queued",
+            JobStatus::Running => "running",
+            JobStatus::Deferred => "deferred",
+            JobStatus::Retrying => "retrying",
+            JobStatus::Succeeded => "succeeded",
+            JobStatus::Failed => "failed",
+            JobStatus::Cancelled => "cancelled",
+        }
+    }
+}
+
+impl FromStr for JobStatus {
+    type Err = String;
+
+    fn from_str(status: &str) -> Result<JobStatus, String> {
+        match status {
+            "queued" => Ok(JobStatus::Queued),
+            "running" => Ok(JobStatus::Running),
+            "deferred" => Ok(JobStatus::Deferred),
+            "retrying" => Ok(JobStatus::Retrying),
+            "succeeded" => Ok(JobStatus::Succeeded),
+            "failed" => Ok(JobStatus::Failed),
+            "cancelled" => Ok(JobStatus::Cancelled),
+            _ => Err(format!("unknown job status {}", status)),
+        }
+    }
+}
+
+/// A `QueueAction` as seen by the user. Bulk actions finish as soon as they've enqueued one job
+/// per day, so their progress is the progress of their children.
+#[derive(Debug, Clone, Serialize, Deserialize, Queryable, Insertable)]
+pub struct Job {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub parent_id: Option<Uuid>,
+    pub params: String,
+    pub status: String,
+    pub attempts: i32,
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub started_at: Option<DateTime<Utc>>,
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+#[derive(GraphQLObject, Debug, Clone, Default, PartialEq)]
+#[graphql(description = "How many of a bulk job's children are in each state")]
+pub struct JobProgress {
+    pub total: i32,
+    pub queued: i32,
+    pub running: i32,
+    pub deferred: i32,
+    pub retrying: i32,
+    pub succeeded: i32,
+    pub failed: i32,
+    pub cancelled: i32,
+}
+
+impl Job {
+    fn new(action: &QueueAction, status: JobStatus) -> Job {
+        Job {
+            id: action.id,
+            user_id: action.user_id,
+            parent_id: action.parent_id,
+            // QueueActionParams is plain data, serializing it can't fail
+            params: serde_json::to_string(&action.params).unwrap_or_default(),
+            status: status.as_str().to_string(),
+            attempts: action.attempts as i32,
+            error: None,
+            created_at: Utc::now(),
+            started_at: None,
+            finished_at: None,
+        }
+    }
+
+    pub fn find_one(conn: &PgConnection, id: &Uuid) -> Result<Job, diesel::result::Error> {
+        Ok(jobs::table.find(id).get_result::<Job>(conn)?)
+    }
+
+    /// The user's most recent jobs, without the ones a bulk job enqueued.
+    pub fn for_user(
+        conn: &PgConnection,
+        the_user_id: &Uuid,
+        limit: i64,
+    ) -> Result<Vec<Job>, diesel::result::Error> {
+        use self::schema::jobs::dsl::*;
+
+        Ok(jobs
+            .filter(user_id.eq(the_user_id).and(parent_id.is_null()))
+            .order(created_at.desc())
+            .limit(limit)
+            .load::<Job>(conn)?)
+    }
+
+    pub fn children(
+        conn: &PgConnection,
+        the_parent_id: &Uuid,
+    ) -> Result<Vec<Job>, diesel::result::Error> {
+        use self::schema::jobs::dsl::*;
+
+        Ok(jobs
+            .filter(parent_id.eq(the_parent_id))
+            .order(created_at.asc())
+            .load::<Job>(conn)?)
+    }
+
+    pub fn progress(
+        conn: &PgConnection,
+        the_parent_id: &Uuid,
+    ) -> Result<JobProgress, diesel::result::Error> {
+        use self::schema::jobs::dsl::*;
+        use diesel::dsl::count_star;
+
+        let counts = jobs
+            .filter(parent_id.eq(the_parent_id))
+            .group_by(status)
+            .select((status, count_star()))
+            .load::<(String, i64)>(conn)?;
+
+        let mut progress = JobProgress::default();
+        for (the_status, count) in counts {
+            let count = count as i32;
+            progress.total += count;
+            match the_status.parse() {
+                Ok(JobStatus::Queued) => progress.queued += count,
+                Ok(JobStatus::Running) => progress.running += count,
+                Ok(JobStatus::Deferred) => progress.deferred += count,
+                Ok(JobStatus::Retrying) => progress.retrying += count,
+                Ok(JobStatus::Succeeded) => progress.succeeded += count,
+                Ok(JobStatus::Failed) => progress.failed += count,
+                Ok(JobStatus::Cancelled) => progress.cancelled += count,
+                Err(_) => (),
+            }
+        }
+
+        Ok(progress)
+    }
+
+    /// Records an action and pushes it onto the queue. A job that already has a row, like a
+    /// requeued dead letter, starts over as queued.
+    pub fn enqueue(
+        conn: &PgConnection,
+        queue: &Queue,
+        action: QueueAction,
+    ) -> Result<Job, AppError> {
+        use self::schema::jobs::dsl::*;
+
+        let job = Job::new(&action, JobStatus::Queued);
+        diesel::insert_into(jobs)
+            .values(&job)
+            .on_conflict(id)
+            .do_update()
+            .set((
+                status.eq(&job.status),
+                attempts.eq(job.attempts),
+                error.eq(None::<String>),
+                started_at.eq(None::<DateTime<Utc>>),
+                finished_at.eq(None::<DateTime<Utc>>),
+            ))
+            .execute(conn)?;
+
+        // the row goes first so a worker that pops the action right away finds it. If the push
+        // fails, the job would otherwise look queued forever.
+        let the_id = action.id;
+        if let Err(e) = queue.push(action) {
+            let job_result = Job::fail(conn, &the_id, 0, "couldn't be queued", false);
+            if let Err(job_err) = job_result {
+                warn!(
+                    "Couldn't record that job {} wasn't queued: {:?}",
+                    the_id, job_err
+                );
+            }
+            return Err(AppError::Queue(e.to_string()));
+        }
+
+        Ok(Job::find_one(conn, &the_id)?)
+    }
+
+    /// Marks an action as picked up by a worker. Creates the job if it was enqueued without one.
+    pub fn start(conn: &PgConnection, action: &QueueAction) -> Result<Job, diesel::result::Error> {
+        use self::schema::jobs::dsl::*;
+
+        let job = Job {
+            started_at: Some(Utc::now()),
+            ..Job::new(action, JobStatus::Running)
+        };
+        diesel::insert_into(jobs)
+            .values(&job)
+            .on_conflict(id)
+            .do_update()
+            .set((
+                status.eq(&job.status),
+                attempts.eq(job.attempts),
+                started_at.eq(job.started_at),
+                // left over from the previous attempt when this is a retry
+                error.eq(None::<String>),
+                finished_at.eq(None::<DateTime<Utc>>),
+            ))
+            .execute(conn)?;
+
+        Job::find_one(conn, &action.id)
+    }
+
+    pub fn status(&self) -> Option<JobStatus> {
+        self.status.parse().ok()
+    }
+
+    /// The user's jobs that haven't finished.
+    fn pending(conn: &PgConnection, the_user_id: &Uuid) -> Result<Vec<Job>, diesel::result::Error> {
+        use self::schema::jobs::dsl::*;
+
+        let pending = vec![
+            JobStatus::Queued.as_str(),
+            JobStatus::Running.as_str(),
+            JobStatus::Deferred.as_str(),
+            JobStatus::Retrying.as_str(),
+        ];
+        jobs.filter(user_id.eq(the_user_id))
+            .filter(status.eq_any(pending))
+            .load::<Job>(conn)
+    }
+
+    /// Whether a sync of the user's `metric`, or any day of it, hasn't finished yet.
+    pub fn sync_pending(
+        conn: &PgConnection,
+        the_user_id: &Uuid,
+        metric: &IntradayMetric,
+    ) -> Result<bool, diesel::result::Error> {
+        Ok(Job::pending(conn, the_user_id)?.iter().any(|job| {
+            match serde_json::from_str::<QueueActionParams>(&job.params) {
+                Ok(QueueActionParams::SyncSince(m, _))
+                | Ok(QueueActionParams::IngestIntraday(m, _)) => &m == metric,
+                _ => false,
+            }
+        }))
+    }
+
+    /// The `days` of the user's `metric` that were ingested successfully, with when that finished.
+    pub fn ingested_days(
+        conn: &PgConnection,
+        the_user_id: &Uuid,
+        metric: &IntradayMetric,
+        days: &[NaiveDate],
+    ) -> Result<Vec<(NaiveDate, DateTime<Utc>)>, diesel::result::Error> {
+        use self::schema::jobs::dsl::*;
+
+        let day_params = days
+            .iter()
+            .map(|day| {
+                let day_params = QueueActionParams::IngestIntraday(metric.clone(), *day);
+                serde_json::to_string(&day_params).unwrap_or_default()
+            })
+            .collect::<Vec<String>>();
+        let ingested = jobs
+            .filter(user_id.eq(the_user_id))
+            .filter(status.eq(JobStatus::Succeeded.as_str()))
+            .filter(params.eq_any(day_params))
+            .select((params, finished_at))
+            .load::<(String, Option<DateTime<Utc>>)>(conn)?;
+
+        Ok(ingested
+            .into_iter()
+            .filter_map(|(job_params, job_finished_at)| {
+                match serde_json::from_str::<QueueActionParams>(&job_params) {
+                    Ok(QueueActionParams::IngestIntraday(_, day)) => Some((day, job_finished_at?)),
+                    _ => None,
+                }
+            })
+            .collect())
+    }
+
+    /// Cancels every job of the user's for `service` that hasn't finished. They stay in the queue,
+    /// workers skip them when they come up. Returns how many were cancelled.
+    pub fn cancel_pending(
+        conn: &PgConnection,
+        the_user_id: &Uuid,
+        service: &str,
+    ) -> Result<usize, diesel::result::Error> {
+        use self::schema::jobs::dsl::*;
+
+        // params are only JSON to the db, so the service is picked out here
+        let ids = Job::pending(conn, the_user_id)?
+            .into_iter()
+            .filter(|job| {
+                serde_json::from_str::<QueueActionParams>(&job.params)
+                    .map(|p| p.service() == service)
+                    .unwrap_or(false)
+            })
+            .map(|job| job.id)
+            .collect::<Vec<_>>();
+
+        diesel::update(jobs.filter(id.eq_any(&ids)))
+            .set((
+                status.eq(JobStatus::Cancelled.as_str()),
+                finished_at.eq(Some(Utc::now())),
+            ))
+            .execute(conn)
+    }
+
+    pub fn succeed(conn: &PgConnection, the_id: &Uuid) -> Result<usize, diesel::result::Error> {
+        use self::schema::jobs::dsl::*;
+
+        diesel::update(jobs.find(the_id))
+            .set((
+                status.eq(JobStatus::Succeeded.as_str()),
+                error.eq(None::<String>),
+                finished_at.eq(Some(Utc::now())),
+            ))
+            .execute(conn)
+    }
+
+    /// Records that the action is waiting until `until` for a rate limit, rather than failing.
+    pub fn defer(
+        conn: &PgConnection,
+        the_id: &Uuid,
+        until: &DateTime<Utc>,
+    ) -> Result<usize, diesel::result::Error> {
+        use self::schema::jobs::dsl::*;
+
+        diesel::update(jobs.find(the_id))
+            .set((
+                status.eq(JobStatus::Deferred.as_str()),
+                error.eq(format!("rate limited until {}", until)),
+            ))
+            .execute(conn)
+    }
+
+    /// Records a failed attempt, and whether the action will be retried.
+    pub fn fail(
+        conn: &PgConnection,
+        the_id: &Uuid,
+        the_attempts: u32,
+        the_error: &str,
+        retrying: bool,
+    ) -> Result<usize, diesel::result::Error> {
+        use self::schema::jobs::dsl::*;
+
+        let (the_status, the_finished_at) = if retrying {
+            (JobStatus::Retrying, None)
+        } else {
+            (JobStatus::Failed, Some(Utc::now()))
+        };
+
+        diesel::update(jobs.find(the_id))
+            .set((
+                status.eq(the_status.as_str()),
+                attempts.eq(the_attempts as i32),
+                error.eq(the_error),
+                finished_at.eq(the_finished_at),
+            ))
+            .execute(conn)
+    }
+}
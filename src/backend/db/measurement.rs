@@ -0,0 +1,166 @@
+//! Shared storage for per-user time series like steps or heart rate.
+//!
+//! Every measurement lives in its own hypertable keyed by `(user_id, time)`, with a `source` and
+//! a `count` column plus any extra columns the metric needs. `measurement!` declares the struct
+//! along with its queries, inserts/upserts and `DbExecutor` handler, so adding a metric means
+//! declaring its table here and implementing `fitbit::IntradayMeasurement` for the parsing.
+use chrono::{DateTime, Utc};
+use diesel::pg::PgConnection;
+use uuid::Uuid;
+
+pub trait Measurement: Sized {
+    /// The hypertable this measurement is stored in.
+    const TABLE: &'static str;
+
+    fn time(&self) -> &DateTime<Utc>;
+
+    /// False for filler rows, ie minutes where Fitbit reports 0 steps.
+    fn is_populated(&self) -> bool;
+
+    fn for_period(
+        conn: &PgConnection,
+        user_id: &Uuid,
+        start: &DateTime<Utc>,
+        end: &DateTime<Utc>,
+    ) -> Result<Vec<Self>, diesel::result::Error>;
+}
+
+macro_rules! measurement {
+    (
+        $(#[$attr:meta])*
+        $name:ident => $table:ident {
+            count: $count_ty:ty
+            $(, $field:ident: $field_ty:ty)* $(,)*
+        }
+    ) => {
+        // `Insertable` looks the table up relative to the calling module
+        use $crate::db::schema::$table;
+
+        $(#[$attr])*
+        #[derive(GraphQLObject, Debug, Clone, Serialize, Deserialize, Queryable, Insertable)]
+        pub struct $name {
+            pub time: chrono::DateTime<chrono::Utc>,
+            pub user_id: uuid::Uuid,
+            pub source: String,
+            pub count: $count_ty,
+            $(pub $field: $field_ty,)*
+        }
+
+        impl $name {
+            pub fn find_one(
+                conn: &diesel::pg::PgConnection,
+                (user_id, time): (&uuid::Uuid, &chrono::DateTime<chrono::Utc>),
+            ) -> Result<$name, diesel::result::Error> {
+                use diesel::prelude::*;
+
+                Ok($table::table
+                    .find((user_id, time))
+                    .get_result::<$name>(conn)?)
+            }
+        }
+
+        impl $crate::db::Measurement for $name {
+            const TABLE: &'static str = stringify!($table);
+
+            fn time(&self) -> &chrono::DateTime<chrono::Utc> {
+                &self.time
+            }
+
+            fn is_populated(&self) -> bool {
+                self.count > <$count_ty>::default()
+            }
+
+            fn for_period(
+                conn: &diesel::pg::PgConnection,
+                the_user_id: &uuid::Uuid,
+                start: &chrono::DateTime<chrono::Utc>,
+                end: &chrono::DateTime<chrono::Utc>,
+            ) -> Result<Vec<$name>, diesel::result::Error> {
+                use $crate::db::schema::$table::dsl::*;
+                use diesel::prelude::*;
+
+                Ok($table
+                    .filter(
+                        user_id
+                            .eq(the_user_id)
+                            .and(time.ge(start).and(time.lt(end))),
+                    )
+                    .order(time.desc())
+                    .load::<$name>(conn)?)
+            }
+        }
+
+        impl $crate::db::Object for $name {
+            fn insert(
+                conn: &diesel::pg::PgConnection,
+                obj: &$name,
+            ) -> Result<$name, diesel::result::Error> {
+                use $crate::db::schema::$table::dsl::*;
+                use diesel::prelude::*;
+
+                diesel::insert_into($table).values(obj).execute(conn)?;
+
+                Ok($name::find_one(conn, (&obj.user_id, &obj.time))?)
+            }
+
+            fn insert_many(
+                conn: &diesel::pg::PgConnection,
+                objs: &[$name],
+            ) -> Result<usize, diesel::result::Error> {
+                use $crate::db::schema::$table::dsl::*;
+                use diesel::prelude::*;
+
+                let mut inserted = 0;
+                for chunk in objs.chunks($crate::db::INSERT_CHUNK_SIZE) {
+                    inserted += diesel::insert_into($table).values(chunk).execute(conn)?;
+                }
+
+                Ok(inserted)
+            }
+        }
+
+        impl $crate::db::Upsert for $name {
+            fn upsert_many(
+                conn: &diesel::pg::PgConnection,
+                objs: &[$name],
+            ) -> Result<$crate::db::UpsertStats, diesel::result::Error> {
+                use $crate::db::schema::$table::dsl::*;
+                use diesel::pg::upsert::excluded;
+                use diesel::prelude::*;
+
+                let mut stats = $crate::db::UpsertStats::default();
+                for chunk in objs.chunks($crate::db::INSERT_CHUNK_SIZE) {
+                    let inserted = diesel::insert_into($table)
+                        .values(chunk)
+                        .on_conflict((user_id, time))
+                        .do_update()
+                        .set((
+                            source.eq(excluded(source)),
+                            count.eq(excluded(count)),
+                            $($field.eq(excluded($field)),)*
+                        ))
+                        .returning($crate::db::was_inserted())
+                        .get_results::<bool>(conn)?;
+                    stats.record(&inserted);
+                }
+
+                Ok(stats)
+            }
+        }
+
+        impl actix::prelude::Message for $name {
+            type Result = Result<$name, $crate::error::AppError>;
+        }
+
+        impl actix::prelude::Handler<$name> for $crate::db::DbExecutor {
+            type Result = Result<$name, $crate::error::AppError>;
+
+            fn handle(&mut self, msg: $name, _: &mut Self::Context) -> Self::Result {
+                use $crate::db::Object;
+
+                let conn = &self.0.get()?;
+                Ok($name::insert(conn, &msg)?)
+            }
+        }
+    };
+}
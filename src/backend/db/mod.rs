@@ -1,40 +1,136 @@
 //! Db executor actor
+use crate::crypto::Keyring;
 use actix::prelude::*;
 use diesel;
+use diesel::dsl::sql;
+use diesel::expression::SqlLiteral;
 use diesel::prelude::*;
 use diesel::r2d2::{self, ConnectionManager};
+use diesel::sql_types::Bool;
 use std::ops::Deref;
+use std::sync::Arc;
 
-pub mod calorie;
-pub mod distance;
-pub mod elevation;
-pub mod floor;
-pub mod step;
-pub use crate::db::calorie::*;
-pub use crate::db::distance::*;
-pub use crate::db::elevation::*;
-pub use crate::db::floor::*;
-pub use crate::db::step::*;
+#[macro_use]
+pub mod measurement;
+pub use crate::db::measurement::Measurement;
 
+pub mod activity;
+pub mod aggregate;
+pub mod body;
+pub mod heart_rate;
+pub use crate::db::activity::*;
+pub use crate::db::aggregate::*;
+pub use crate::db::body::*;
+pub use crate::db::heart_rate::*;
+
+pub mod access_token;
+pub mod grant;
+pub mod identity;
 pub mod token;
 pub mod user;
+pub use crate::db::access_token::*;
+pub use crate::db::grant::*;
+pub use crate::db::identity::*;
 pub use crate::db::token::*;
 pub use crate::db::user::*;
 
 pub mod mood;
 pub use crate::db::mood::*;
 
+pub mod job;
+pub use crate::db::job::*;
+
+pub mod sync_cursor;
+pub mod sync_setting;
+pub use crate::db::sync_cursor::*;
+pub use crate::db::sync_setting::*;
+
+pub mod sleep;
+pub use crate::db::sleep::*;
+
 pub mod schema;
 
 pub type Pool = r2d2::Pool<ConnectionManager<PgConnection>>;
 
+/// Postgres caps a single statement at 65535 bind params, so big batches are inserted in chunks.
+pub const INSERT_CHUNK_SIZE: usize = 10_000;
+
 pub trait Object: Sized {
     fn insert(conn: &PgConnection, obj: &Self) -> Result<Self, diesel::result::Error>;
     fn insert_many(conn: &PgConnection, objs: &[Self]) -> Result<usize, diesel::result::Error>;
 }
 
-/// This is db executor actor. We are going to run 3 of them in parallel.
-pub struct DbExecutor(pub Pool);
+/// Ingested data is upserted, so re-syncing a day that's already (partly) there is safe.
+pub trait Upsert: Sized {
+    fn upsert_many(
+        conn: &PgConnection,
+        objs: &[Self],
+    ) -> Result<UpsertStats, diesel::result::Error>;
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct UpsertStats {
+    pub inserted: usize,
+    pub updated: usize,
+}
+
+impl UpsertStats {
+    pub fn record(&mut self, inserted_flags: &[bool]) {
+        for &was_inserted in inserted_flags {
+            if was_inserted {
+                self.inserted += 1;
+            } else {
+                self.updated += 1;
+            }
+        }
+    }
+}
+
+/// `RETURNING` this from an upsert to tell inserted rows (true) apart from updated ones (false).
+pub fn was_inserted() -> SqlLiteral<Bool> {
+    sql::<Bool>("xmax = 0")
+}
+
+/// Every table of data ingested from somewhere, with a `source` column saying where. Moods are
+/// entered by the user, so they aren't one of them.
+pub static SOURCED_TABLES: [&'static str; 12] = [
+    "body_measurements",
+    "calories",
+    "distances",
+    "elevations",
+    "floors",
+    "heart_rate_minutes",
+    "heart_rate_zones",
+    "heart_rates",
+    "resting_heart_rates",
+    "sleep_stages",
+    "sleeps",
+    "steps",
+];
+
+/// Deletes everything of the user's that came from `source`. Returns how many rows were deleted.
+pub fn delete_from_source(
+    conn: &PgConnection,
+    user_id: &uuid::Uuid,
+    source: &str,
+) -> Result<usize, diesel::result::Error> {
+    let mut deleted = 0;
+    for table in SOURCED_TABLES.iter() {
+        deleted += diesel::sql_query(format!(
+            "DELETE FROM {} WHERE user_id = $1 AND source = $2",
+            table
+        ))
+        .bind::<diesel::sql_types::Uuid, _>(user_id)
+        .bind::<diesel::sql_types::Text, _>(source)
+        .execute(conn)?;
+    }
+
+    Ok(deleted)
+}
+
+/// This is db executor actor. We are going to run 3 of them in parallel. The keyring is for
+/// sealing and opening tokens.
+pub struct DbExecutor(pub Pool, pub Arc<Keyring>);
 
 impl Actor for DbExecutor {
     type Context = SyncContext<Self>;
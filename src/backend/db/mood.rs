@@ -7,7 +7,7 @@ use diesel::prelude::*;
 use uuid::Uuid;
 
 use crate::db::{schema, DbExecutor, Handler, Message, Object};
-use actix_web::{error, Error};
+use crate::error::AppError;
 
 #[derive(GraphQLObject, Debug, Clone, Serialize, Deserialize, Queryable, Insertable)]
 #[graphql(description = "A single mood datapoint")]
@@ -69,15 +69,14 @@ impl Object for Mood {
 }
 
 impl Message for Mood {
-    type Result = Result<Mood, Error>;
+    type Result = Result<Mood, AppError>;
 }
 
 impl Handler<Mood> for DbExecutor {
-    type Result = Result<Mood, Error>;
+    type Result = Result<Mood, AppError>;
 
     fn handle(&mut self, msg: Mood, _: &mut Self::Context) -> Self::Result {
-        let conn = &self.0.get().unwrap();
-        Ok(Mood::insert(conn, &msg)
-            .map_err(|_| error::ErrorInternalServerError("Error inserting mood"))?)
+        let conn = &self.0.get()?;
+        Ok(Mood::insert(conn, &msg)?)
     }
 }
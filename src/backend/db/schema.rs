@@ -1,5 +1,29 @@
 #![allow(proc_macro_derive_resolution_fallback)]
 
+table! {
+    access_tokens (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        name -> Text,
+        prefix -> Text,
+        token_hash -> Text,
+        scope -> Text,
+        created_at -> Timestamptz,
+        expires_at -> Nullable<Timestamptz>,
+        last_used_at -> Nullable<Timestamptz>,
+    }
+}
+
+table! {
+    body_measurements (user_id, time, kind) {
+        time -> Timestamptz,
+        user_id -> Uuid,
+        kind -> Text,
+        source -> Text,
+        value -> Float8,
+    }
+}
+
 table! {
     calories (user_id, time) {
         time -> Timestamptz,
@@ -38,6 +62,75 @@ table! {
     }
 }
 
+table! {
+    grants (id) {
+        id -> Uuid,
+        owner_id -> Uuid,
+        grantee_id -> Uuid,
+        metrics -> Array<Text>,
+        start_time -> Nullable<Timestamptz>,
+        end_time -> Nullable<Timestamptz>,
+        expires_at -> Nullable<Timestamptz>,
+        created_at -> Timestamptz,
+    }
+}
+
+table! {
+    heart_rate_minutes (user_id, time) {
+        time -> Timestamptz,
+        user_id -> Uuid,
+        source -> Text,
+        count -> Int4,
+    }
+}
+
+table! {
+    heart_rate_zones (user_id, time, name) {
+        time -> Timestamptz,
+        user_id -> Uuid,
+        source -> Text,
+        name -> Text,
+        min -> Int4,
+        max -> Int4,
+        minutes -> Int4,
+        calories_out -> Float8,
+    }
+}
+
+table! {
+    heart_rates (user_id, time) {
+        time -> Timestamptz,
+        user_id -> Uuid,
+        source -> Text,
+        count -> Int4,
+    }
+}
+
+table! {
+    identities (provider, subject) {
+        provider -> Text,
+        subject -> Text,
+        user_id -> Uuid,
+        email -> Nullable<Text>,
+        created_at -> Timestamptz,
+    }
+}
+
+table! {
+    jobs (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        parent_id -> Nullable<Uuid>,
+        params -> Text,
+        status -> Text,
+        attempts -> Int4,
+        error -> Nullable<Text>,
+        created_at -> Timestamptz,
+        started_at -> Nullable<Timestamptz>,
+        finished_at -> Nullable<Timestamptz>,
+    }
+}
+
 table! {
     moods (user_id, time) {
         time -> Timestamptz,
@@ -47,6 +140,40 @@ table! {
     }
 }
 
+table! {
+    resting_heart_rates (user_id, time) {
+        time -> Timestamptz,
+        user_id -> Uuid,
+        source -> Text,
+        count -> Int4,
+    }
+}
+
+table! {
+    sleep_stages (user_id, time) {
+        time -> Timestamptz,
+        user_id -> Uuid,
+        source -> Text,
+        level -> Text,
+        seconds -> Int4,
+    }
+}
+
+table! {
+    sleeps (user_id, start_time) {
+        start_time -> Timestamptz,
+        end_time -> Timestamptz,
+        user_id -> Uuid,
+        source -> Text,
+        log_id -> Int8,
+        is_main_sleep -> Bool,
+        efficiency -> Int4,
+        minutes_asleep -> Int4,
+        minutes_awake -> Int4,
+        time_in_bed -> Int4,
+    }
+}
+
 table! {
     steps (user_id, time) {
         time -> Timestamptz,
@@ -56,6 +183,27 @@ table! {
     }
 }
 
+table! {
+    sync_cursors (user_id, service, metric) {
+        user_id -> Uuid,
+        service -> Text,
+        metric -> Text,
+        synced_through -> Date,
+        updated_at -> Timestamptz,
+    }
+}
+
+table! {
+    sync_settings (user_id, service) {
+        user_id -> Uuid,
+        service -> Text,
+        interval_minutes -> Int4,
+        metrics -> Array<Text>,
+        enabled -> Bool,
+        last_scheduled_at -> Nullable<Timestamptz>,
+    }
+}
+
 table! {
     tokens (id) {
         id -> Uuid,
@@ -65,6 +213,13 @@ table! {
         access_token -> Text,
         access_token_expiry -> Timestamptz,
         refresh_token -> Text,
+        timezone -> Nullable<Text>,
+        utc_offset_ms -> Nullable<Int4>,
+        data_key -> Nullable<Text>,
+        key_id -> Nullable<Text>,
+        scopes -> Array<Text>,
+        refresh_error -> Nullable<Text>,
+        refresh_failed_at -> Nullable<Timestamptz>,
     }
 }
 
@@ -72,25 +227,50 @@ table! {
     users (id) {
         id -> Uuid,
         email -> Text,
-        g_sub -> Text,
+        is_admin -> Bool,
     }
 }
 
+joinable!(access_tokens -> users (user_id));
+joinable!(body_measurements -> users (user_id));
 joinable!(calories -> users (user_id));
 joinable!(distances -> users (user_id));
 joinable!(elevations -> users (user_id));
 joinable!(floors -> users (user_id));
+joinable!(heart_rate_minutes -> users (user_id));
+joinable!(heart_rate_zones -> users (user_id));
+joinable!(heart_rates -> users (user_id));
+joinable!(identities -> users (user_id));
+joinable!(jobs -> users (user_id));
 joinable!(moods -> users (user_id));
+joinable!(resting_heart_rates -> users (user_id));
+joinable!(sleep_stages -> users (user_id));
+joinable!(sleeps -> users (user_id));
 joinable!(steps -> users (user_id));
+joinable!(sync_cursors -> users (user_id));
+joinable!(sync_settings -> users (user_id));
 joinable!(tokens -> users (user_id));
 
 allow_tables_to_appear_in_same_query!(
+    access_tokens,
+    body_measurements,
     calories,
     distances,
     elevations,
     floors,
+    grants,
+    heart_rate_minutes,
+    heart_rate_zones,
+    heart_rates,
+    identities,
+    jobs,
     moods,
+    resting_heart_rates,
+    sleep_stages,
+    sleeps,
     steps,
+    sync_cursors,
+    sync_settings,
     tokens,
     users,
 );
@@ -0,0 +1,209 @@
+#![allow(proc_macro_derive_resolution_fallback)]
+
+use super::schema::{sleep_stages, sleeps};
+use chrono::{DateTime, Utc};
+use diesel::pg::upsert::excluded;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use uuid::Uuid;
+
+use crate::db::{
+    schema, was_inserted, DbExecutor, Handler, Message, Object, Upsert, UpsertStats,
+    INSERT_CHUNK_SIZE,
+};
+use crate::error::AppError;
+
+#[derive(Debug, Clone, Serialize, Deserialize, Queryable, Insertable)]
+pub struct Sleep {
+    pub start_time: DateTime<Utc>,
+    pub end_time: DateTime<Utc>,
+    pub user_id: Uuid,
+    pub source: String,
+    pub log_id: i64,
+    pub is_main_sleep: bool,
+    pub efficiency: i32,
+    pub minutes_asleep: i32,
+    pub minutes_awake: i32,
+    pub time_in_bed: i32,
+}
+
+#[derive(GraphQLObject, Debug, Clone, Serialize, Deserialize, Queryable, Insertable)]
+#[graphql(description = "A single sleep stage segment")]
+pub struct SleepStage {
+    pub time: DateTime<Utc>,
+    pub user_id: Uuid,
+    pub source: String,
+    pub level: String,
+    pub seconds: i32,
+}
+
+impl Sleep {
+    /// Every sleep that overlaps [start, end), so last night's sleep shows up in today's range.
+    pub fn for_period(
+        conn: &PgConnection,
+        the_user_id: &Uuid,
+        start: &DateTime<Utc>,
+        end: &DateTime<Utc>,
+    ) -> Result<Vec<Sleep>, diesel::result::Error> {
+        use self::schema::sleeps::dsl::*;
+
+        Ok(sleeps
+            .filter(
+                user_id
+                    .eq(the_user_id)
+                    .and(end_time.ge(start).and(start_time.lt(end))),
+            )
+            .order(start_time.desc())
+            .load::<Sleep>(conn)?)
+    }
+
+    pub fn find_one(
+        conn: &PgConnection,
+        (user_id, start_time): (&Uuid, &DateTime<Utc>),
+    ) -> Result<Sleep, diesel::result::Error> {
+        Ok(sleeps::table
+            .find((user_id, start_time))
+            .get_result::<Sleep>(conn)?)
+    }
+}
+
+impl Object for Sleep {
+    fn insert(conn: &PgConnection, sleep: &Sleep) -> Result<Sleep, diesel::result::Error> {
+        use self::schema::sleeps::dsl::*;
+
+        diesel::insert_into(sleeps).values(sleep).execute(conn)?;
+
+        Ok(Sleep::find_one(conn, (&sleep.user_id, &sleep.start_time))?)
+    }
+
+    fn insert_many(
+        conn: &PgConnection,
+        the_sleeps: &[Sleep],
+    ) -> Result<usize, diesel::result::Error> {
+        use self::schema::sleeps::dsl::*;
+
+        diesel::insert_into(sleeps).values(the_sleeps).execute(conn)
+    }
+}
+
+impl Upsert for Sleep {
+    fn upsert_many(
+        conn: &PgConnection,
+        the_sleeps: &[Sleep],
+    ) -> Result<UpsertStats, diesel::result::Error> {
+        use self::schema::sleeps::dsl::*;
+
+        let mut stats = UpsertStats::default();
+        for chunk in the_sleeps.chunks(INSERT_CHUNK_SIZE) {
+            let inserted = diesel::insert_into(sleeps)
+                .values(chunk)
+                .on_conflict((user_id, start_time))
+                .do_update()
+                .set((
+                    end_time.eq(excluded(end_time)),
+                    source.eq(excluded(source)),
+                    log_id.eq(excluded(log_id)),
+                    is_main_sleep.eq(excluded(is_main_sleep)),
+                    efficiency.eq(excluded(efficiency)),
+                    minutes_asleep.eq(excluded(minutes_asleep)),
+                    minutes_awake.eq(excluded(minutes_awake)),
+                    time_in_bed.eq(excluded(time_in_bed)),
+                ))
+                .returning(was_inserted())
+                .get_results::<bool>(conn)?;
+            stats.record(&inserted);
+        }
+
+        Ok(stats)
+    }
+}
+
+impl SleepStage {
+    pub fn for_period(
+        conn: &PgConnection,
+        the_user_id: &Uuid,
+        start: &DateTime<Utc>,
+        end: &DateTime<Utc>,
+    ) -> Result<Vec<SleepStage>, diesel::result::Error> {
+        use self::schema::sleep_stages::dsl::*;
+
+        Ok(sleep_stages
+            .filter(
+                user_id
+                    .eq(the_user_id)
+                    .and(time.ge(start).and(time.lt(end))),
+            )
+            .order(time.asc())
+            .load::<SleepStage>(conn)?)
+    }
+
+    /// Fitbit can re-score a sleep, so stages are replaced wholesale rather than upserted.
+    pub fn replace_for_period(
+        conn: &PgConnection,
+        the_user_id: &Uuid,
+        start: &DateTime<Utc>,
+        end: &DateTime<Utc>,
+        the_stages: &[SleepStage],
+    ) -> Result<usize, diesel::result::Error> {
+        use self::schema::sleep_stages::dsl::*;
+
+        diesel::delete(
+            sleep_stages.filter(
+                user_id
+                    .eq(the_user_id)
+                    .and(time.ge(start).and(time.lt(end))),
+            ),
+        )
+        .execute(conn)?;
+
+        SleepStage::insert_many(conn, the_stages)
+    }
+
+    pub fn find_one(
+        conn: &PgConnection,
+        (user_id, time): (&Uuid, &DateTime<Utc>),
+    ) -> Result<SleepStage, diesel::result::Error> {
+        Ok(sleep_stages::table
+            .find((user_id, time))
+            .get_result::<SleepStage>(conn)?)
+    }
+}
+
+impl Object for SleepStage {
+    fn insert(
+        conn: &PgConnection,
+        stage: &SleepStage,
+    ) -> Result<SleepStage, diesel::result::Error> {
+        use self::schema::sleep_stages::dsl::*;
+
+        diesel::insert_into(sleep_stages)
+            .values(stage)
+            .execute(conn)?;
+
+        Ok(SleepStage::find_one(conn, (&stage.user_id, &stage.time))?)
+    }
+
+    fn insert_many(
+        conn: &PgConnection,
+        the_stages: &[SleepStage],
+    ) -> Result<usize, diesel::result::Error> {
+        use self::schema::sleep_stages::dsl::*;
+
+        diesel::insert_into(sleep_stages)
+            .values(the_stages)
+            .execute(conn)
+    }
+}
+
+impl Message for Sleep {
+    type Result = Result<Sleep, AppError>;
+}
+
+impl Handler<Sleep> for DbExecutor {
+    type Result = Result<Sleep, AppError>;
+
+    fn handle(&mut self, msg: Sleep, _: &mut Self::Context) -> Self::Result {
+        let conn = &self.0.get()?;
+        Ok(Sleep::insert(conn, &msg)?)
+    }
+}
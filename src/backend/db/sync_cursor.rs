@@ -0,0 +1,132 @@
+#![allow(proc_macro_derive_resolution_fallback)]
+
+use super::schema::sync_cursors;
+use chrono::{DateTime, NaiveDate, Utc};
+use chrono_tz::Tz;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::sql_types::{Date, Text, Uuid as SqlUuid};
+use uuid::Uuid;
+
+use crate::db::schema;
+
+/// The last day of a metric up to which every day was synced completely. Days after it still need
+/// syncing, some of them may already be done if they finished before an earlier day did.
+#[derive(Debug, Clone, Serialize, Deserialize, Queryable, Insertable)]
+#[table_name = "sync_cursors"]
+pub struct SyncCursor {
+    pub user_id: Uuid,
+    pub service: String,
+    /// `IntradayMetric::as_str`
+    pub metric: String,
+    pub synced_through: NaiveDate,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(QueryableByName, Debug)]
+struct MissingDay {
+    #[sql_type = "Date"]
+    day: NaiveDate,
+}
+
+impl SyncCursor {
+    pub fn for_user(
+        conn: &PgConnection,
+        the_user_id: &Uuid,
+    ) -> Result<Vec<SyncCursor>, diesel::result::Error> {
+        use self::schema::sync_cursors::dsl::*;
+
+        Ok(sync_cursors
+            .filter(user_id.eq(the_user_id))
+            .order((service.asc(), metric.asc()))
+            .load::<SyncCursor>(conn)?)
+    }
+
+    pub fn find_one(
+        conn: &PgConnection,
+        (user_id, service, metric): (&Uuid, &str, &str),
+    ) -> Result<SyncCursor, diesel::result::Error> {
+        Ok(sync_cursors::table
+            .find((user_id, service, metric))
+            .get_result::<SyncCursor>(conn)?)
+    }
+
+    pub fn delete_for_service(
+        conn: &PgConnection,
+        the_user_id: &Uuid,
+        the_service: &str,
+    ) -> Result<usize, diesel::result::Error> {
+        use self::schema::sync_cursors::dsl::*;
+
+        diesel::delete(sync_cursors.filter(user_id.eq(the_user_id).and(service.eq(the_service))))
+            .execute(conn)
+    }
+
+    /// Puts a new cursor at `day`, the day before a metric's first sync. Leaves an existing one
+    /// alone.
+    pub fn start(
+        conn: &PgConnection,
+        (the_user_id, the_service, the_metric): (&Uuid, &str, &str),
+        day: NaiveDate,
+    ) -> Result<usize, diesel::result::Error> {
+        use self::schema::sync_cursors::dsl::*;
+
+        diesel::insert_into(sync_cursors)
+            .values((
+                user_id.eq(the_user_id),
+                service.eq(the_service),
+                metric.eq(the_metric),
+                synced_through.eq(day),
+            ))
+            .on_conflict_do_nothing()
+            .execute(conn)
+    }
+
+    /// Moves the cursor from `from` up to `to`. Does nothing if it has moved since it was read,
+    /// so two workers can't move it past a day only one of them knows about.
+    pub fn advance(
+        conn: &PgConnection,
+        key: (&Uuid, &str, &str),
+        (from, to): (NaiveDate, NaiveDate),
+    ) -> Result<usize, diesel::result::Error> {
+        use self::schema::sync_cursors::dsl::*;
+
+        diesel::update(sync_cursors.find(key).filter(synced_through.eq(from)))
+            .set((synced_through.eq(to), updated_at.eq(Utc::now())))
+            .execute(conn)
+    }
+}
+
+/// Local days between `start` and `end` (inclusive) without a single row of the user's in
+/// `table`. Some of these are legitimately empty (a tracker that wasn't worn), syncing them again
+/// is just a wasted request.
+pub fn missing_days(
+    conn: &PgConnection,
+    table: &str,
+    user_id: &Uuid,
+    (start, end): (NaiveDate, NaiveDate),
+    tz: Tz,
+) -> Result<Vec<NaiveDate>, diesel::result::Error> {
+    // `table` is one of our own table names, never user input
+    let query = format!(
+        "SELECT day::DATE AS day \
+         FROM generate_series($3::DATE, $4::DATE, INTERVAL '1 day') AS day \
+         WHERE NOT EXISTS ( \
+           SELECT 1 FROM {} \
+           WHERE user_id = $1 \
+           AND time >= day::TIMESTAMP AT TIME ZONE $2 \
+           AND time < (day + INTERVAL '1 day')::TIMESTAMP AT TIME ZONE $2 \
+         ) \
+         ORDER BY day",
+        table
+    );
+
+    let days = diesel::sql_query(query)
+        .bind::<SqlUuid, _>(user_id)
+        .bind::<Text, _>(tz.name())
+        .bind::<Date, _>(start)
+        .bind::<Date, _>(end)
+        .load::<MissingDay>(conn)?;
+
+    Ok(days.into_iter().map(|d| d.day).collect())
+}
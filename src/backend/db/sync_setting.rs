@@ -0,0 +1,111 @@
+#![allow(proc_macro_derive_resolution_fallback)]
+
+use super::schema::sync_settings;
+use chrono::{DateTime, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use uuid::Uuid;
+
+use crate::db::schema;
+
+/// Fitbit allows 150 requests per user per hour, and a sync takes up to 12.
+pub static MIN_SYNC_INTERVAL_MINUTES: i32 = 15;
+
+#[derive(Debug, Clone, Serialize, Deserialize, Queryable, QueryableByName, Insertable)]
+#[table_name = "sync_settings"]
+pub struct SyncSetting {
+    pub user_id: Uuid,
+    pub service: String,
+    pub interval_minutes: i32,
+    /// `IntradayMetric::as_str` of every metric to sync
+    pub metrics: Vec<String>,
+    pub enabled: bool,
+    pub last_scheduled_at: Option<DateTime<Utc>>,
+}
+
+#[derive(AsChangeset)]
+#[table_name = "sync_settings"]
+pub struct UpdateSyncSetting {
+    pub interval_minutes: Option<i32>,
+    pub metrics: Option<Vec<String>>,
+    pub enabled: Option<bool>,
+}
+
+impl SyncSetting {
+    pub fn for_user(
+        conn: &PgConnection,
+        the_user_id: &Uuid,
+    ) -> Result<Vec<SyncSetting>, diesel::result::Error> {
+        use self::schema::sync_settings::dsl::*;
+
+        Ok(sync_settings
+            .filter(user_id.eq(the_user_id))
+            .order(service.asc())
+            .load::<SyncSetting>(conn)?)
+    }
+
+    pub fn find_one(
+        conn: &PgConnection,
+        (user_id, service): (&Uuid, &str),
+    ) -> Result<SyncSetting, diesel::result::Error> {
+        Ok(sync_settings::table
+            .find((user_id, service))
+            .get_result::<SyncSetting>(conn)?)
+    }
+
+    /// Applies `update` to the user's settings for `service`, starting from the defaults if there
+    /// aren't any yet.
+    pub fn update(
+        conn: &PgConnection,
+        the_user_id: &Uuid,
+        the_service: &str,
+        update: &UpdateSyncSetting,
+    ) -> Result<SyncSetting, diesel::result::Error> {
+        use self::schema::sync_settings::dsl::*;
+
+        diesel::insert_into(sync_settings)
+            .values((user_id.eq(the_user_id), service.eq(the_service)))
+            .on_conflict_do_nothing()
+            .execute(conn)?;
+
+        // an empty changeset is an error in diesel
+        if update.interval_minutes.is_some() || update.metrics.is_some() || update.enabled.is_some()
+        {
+            diesel::update(sync_settings.find((the_user_id, the_service)))
+                .set(update)
+                .execute(conn)?;
+        }
+
+        SyncSetting::find_one(conn, (the_user_id, the_service))
+    }
+
+    /// Gives every user connected to `service` the default settings, if they have none yet.
+    pub fn create_missing(
+        conn: &PgConnection,
+        the_service: &str,
+    ) -> Result<usize, diesel::result::Error> {
+        diesel::sql_query(
+            "INSERT INTO sync_settings (user_id, service) \
+             SELECT user_id, service FROM tokens WHERE service = $1 \
+             ON CONFLICT DO NOTHING",
+        )
+        .bind::<diesel::sql_types::Text, _>(the_service)
+        .execute(conn)
+    }
+
+    /// Claims every enabled setting whose interval has passed, for users that are still connected.
+    /// Claiming bumps `last_scheduled_at` in the same statement, so several schedulers can run at
+    /// once without syncing anyone twice.
+    pub fn claim_due(conn: &PgConnection) -> Result<Vec<SyncSetting>, diesel::result::Error> {
+        diesel::sql_query(
+            "UPDATE sync_settings s SET last_scheduled_at = NOW() \
+             WHERE s.enabled \
+             AND (s.last_scheduled_at IS NULL \
+                  OR s.last_scheduled_at + s.interval_minutes * INTERVAL '1 minute' <= NOW()) \
+             AND EXISTS (SELECT 1 FROM tokens t \
+                         WHERE t.user_id = s.user_id AND t.service = s.service) \
+             RETURNING s.*",
+        )
+        .load::<SyncSetting>(conn)
+    }
+}
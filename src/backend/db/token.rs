@@ -2,14 +2,18 @@
 
 use super::schema::tokens;
 use super::user::User;
+use crate::crypto::Keyring;
 use crate::db::{self, schema, DbExecutor, Handler, Message};
-use actix_web::{error, Error};
+use crate::error::AppError;
 use chrono::{DateTime, Utc};
+use diesel::pg::upsert::excluded;
 use diesel::pg::PgConnection;
 use diesel::prelude::*;
 use uuid::Uuid;
 
-#[derive(Identifiable, Associations, Debug, Clone, Serialize, Queryable)]
+/// A connected service's tokens, decrypted. Only `TokenRow`s are ever read from or written to the
+/// table, so nothing outside this file sees the ciphertexts.
+#[derive(Identifiable, Associations, Debug, Clone, Serialize)]
 #[belongs_to(User)]
 #[table_name = "tokens"]
 pub struct Token {
@@ -20,10 +24,14 @@ pub struct Token {
     pub access_token: String,
     pub access_token_expiry: DateTime<Utc>,
     pub refresh_token: String,
+    pub timezone: Option<String>,
+    pub utc_offset_ms: Option<i32>,
+    pub scopes: Vec<String>,
+    /// Why the last refresh failed, if it did. Cleared once the tokens are updated again.
+    pub refresh_error: Option<String>,
+    pub refresh_failed_at: Option<DateTime<Utc>>,
 }
 
-#[derive(Insertable)]
-#[table_name = "tokens"]
 pub struct NewToken<'a> {
     pub id: &'a Uuid,
     pub user_id: &'a Uuid,
@@ -32,46 +40,370 @@ pub struct NewToken<'a> {
     pub access_token: &'a str,
     pub access_token_expiry: &'a DateTime<Utc>,
     pub refresh_token: &'a str,
+    pub timezone: Option<&'a str>,
+    pub utc_offset_ms: Option<i32>,
+    pub scopes: &'a [String],
 }
 
-#[derive(AsChangeset)]
-#[table_name = "tokens"]
+/// Fields left `None` are kept as they are.
 pub struct UpdateToken<'a> {
     pub access_token: Option<&'a str>,
     pub access_token_expiry: Option<&'a DateTime<Utc>>,
     pub service_userid: Option<&'a str>,
     pub refresh_token: Option<&'a str>,
+    pub timezone: Option<&'a str>,
+    pub utc_offset_ms: Option<i32>,
+    pub scopes: Option<&'a [String]>,
+}
+
+/// A token as stored. The access and refresh tokens are sealed in an envelope under `key_id`, or
+/// plaintext if that's NULL (rows from before encryption that `qs-worker encrypt-tokens` hasn't
+/// gotten to yet).
+#[derive(Queryable, Insertable, AsChangeset)]
+#[table_name = "tokens"]
+#[changeset_options(treat_none_as_null = "true")]
+struct TokenRow {
+    id: Uuid,
+    user_id: Uuid,
+    service: String,
+    service_userid: String,
+    access_token: String,
+    access_token_expiry: DateTime<Utc>,
+    refresh_token: String,
+    timezone: Option<String>,
+    utc_offset_ms: Option<i32>,
+    data_key: Option<String>,
+    key_id: Option<String>,
+    scopes: Vec<String>,
+    refresh_error: Option<String>,
+    refresh_failed_at: Option<DateTime<Utc>>,
+}
+
+/// Every sealed value is bound to the user and service it belongs to.
+fn aads(user_id: &Uuid, service: &str) -> (String, String, String) {
+    (
+        format!("data_key:{}:{}", user_id, service),
+        format!("access_token:{}:{}", user_id, service),
+        format!("refresh_token:{}:{}", user_id, service),
+    )
+}
+
+impl TokenRow {
+    fn seal(token: &Token, keyring: &Keyring) -> Result<TokenRow, diesel::result::Error> {
+        let (key_aad, access_aad, refresh_aad) = aads(&token.user_id, &token.service);
+        let envelope = keyring
+            .seal(
+                &key_aad,
+                &[
+                    (token.access_token.as_str(), access_aad.as_str()),
+                    (token.refresh_token.as_str(), refresh_aad.as_str()),
+                ],
+            )
+            .map_err(|e| diesel::result::Error::SerializationError(Box::new(e)))?;
+        let mut ciphertexts = envelope.ciphertexts.into_iter();
+
+        Ok(TokenRow {
+            id: token.id,
+            user_id: token.user_id,
+            service: token.service.clone(),
+            service_userid: token.service_userid.clone(),
+            access_token: ciphertexts.next().unwrap_or_default(),
+            access_token_expiry: token.access_token_expiry,
+            refresh_token: ciphertexts.next().unwrap_or_default(),
+            timezone: token.timezone.clone(),
+            utc_offset_ms: token.utc_offset_ms,
+            data_key: Some(envelope.data_key),
+            key_id: Some(envelope.key_id),
+            scopes: token.scopes.clone(),
+            refresh_error: token.refresh_error.clone(),
+            refresh_failed_at: token.refresh_failed_at,
+        })
+    }
+
+    fn open(self, keyring: &Keyring) -> Result<Token, diesel::result::Error> {
+        let (access_token, refresh_token) = match (&self.key_id, &self.data_key) {
+            (Some(the_key_id), Some(the_data_key)) => {
+                let (key_aad, access_aad, refresh_aad) = aads(&self.user_id, &self.service);
+                let mut plaintexts = keyring
+                    .open(
+                        the_key_id,
+                        the_data_key,
+                        &key_aad,
+                        &[
+                            (self.access_token.as_str(), access_aad.as_str()),
+                            (self.refresh_token.as_str(), refresh_aad.as_str()),
+                        ],
+                    )
+                    .map_err(|e| diesel::result::Error::DeserializationError(Box::new(e)))?
+                    .into_iter();
+                (
+                    plaintexts.next().unwrap_or_default(),
+                    plaintexts.next().unwrap_or_default(),
+                )
+            }
+            _ => (self.access_token, self.refresh_token),
+        };
+
+        Ok(Token {
+            id: self.id,
+            user_id: self.user_id,
+            service: self.service,
+            service_userid: self.service_userid,
+            access_token,
+            access_token_expiry: self.access_token_expiry,
+            refresh_token,
+            timezone: self.timezone,
+            utc_offset_ms: self.utc_offset_ms,
+            scopes: self.scopes,
+            refresh_error: self.refresh_error,
+            refresh_failed_at: self.refresh_failed_at,
+        })
+    }
+}
+
+fn open_all(rows: Vec<TokenRow>, keyring: &Keyring) -> Result<Vec<Token>, diesel::result::Error> {
+    rows.into_iter().map(|row| row.open(keyring)).collect()
 }
 
 impl Token {
-    pub fn find_one(conn: &PgConnection, id: Uuid) -> Result<Token, diesel::result::Error> {
-        Ok(tokens::table.find(id).get_result::<Token>(conn)?)
+    pub fn find_one(
+        conn: &PgConnection,
+        keyring: &Keyring,
+        id: Uuid,
+    ) -> Result<Token, diesel::result::Error> {
+        tokens::table
+            .find(id)
+            .get_result::<TokenRow>(conn)?
+            .open(keyring)
+    }
+
+    /// Like `find_one`, but the row stays locked until the transaction ends.
+    fn lock(
+        conn: &PgConnection,
+        keyring: &Keyring,
+        id: Uuid,
+    ) -> Result<Token, diesel::result::Error> {
+        tokens::table
+            .find(id)
+            .for_update()
+            .get_result::<TokenRow>(conn)?
+            .open(keyring)
+    }
+
+    pub fn for_user(
+        conn: &PgConnection,
+        keyring: &Keyring,
+        the_user_id: &Uuid,
+    ) -> Result<Vec<Token>, diesel::result::Error> {
+        use self::schema::tokens::dsl::*;
+
+        open_all(
+            tokens
+                .filter(user_id.eq(the_user_id))
+                .order(service.asc())
+                .load::<TokenRow>(conn)?,
+            keyring,
+        )
     }
 
     pub fn find_by_uid_service(
         conn: &PgConnection,
+        keyring: &Keyring,
         the_user_id: &Uuid,
         the_service: &str,
     ) -> Result<Token, diesel::result::Error> {
         use self::schema::tokens::dsl::*;
 
-        let mut items = tokens
-            .filter(user_id.eq(the_user_id).and(service.eq(the_service)))
-            .load::<Token>(conn)?;
+        let mut items = open_all(
+            tokens
+                .filter(user_id.eq(the_user_id).and(service.eq(the_service)))
+                .load::<TokenRow>(conn)?,
+            keyring,
+        )?;
 
         // todo assert at most one
-        Ok(items.pop().unwrap())
+        items.pop().ok_or(diesel::result::Error::NotFound)
+    }
+
+    /// Finds whose token it is from the id the service knows them by.
+    pub fn find_by_service_userid(
+        conn: &PgConnection,
+        keyring: &Keyring,
+        the_service: &str,
+        the_service_userid: &str,
+    ) -> Result<Token, diesel::result::Error> {
+        use self::schema::tokens::dsl::*;
+
+        tokens
+            .filter(
+                service
+                    .eq(the_service)
+                    .and(service_userid.eq(the_service_userid)),
+            )
+            .first::<TokenRow>(conn)?
+            .open(keyring)
+    }
+
+    fn from_new(token: &NewToken) -> Token {
+        Token {
+            id: *token.id,
+            user_id: *token.user_id,
+            service: token.service.to_string(),
+            service_userid: token.service_userid.to_string(),
+            access_token: token.access_token.to_string(),
+            access_token_expiry: *token.access_token_expiry,
+            refresh_token: token.refresh_token.to_string(),
+            timezone: token.timezone.map(String::from),
+            utc_offset_ms: token.utc_offset_ms,
+            scopes: token.scopes.to_vec(),
+            refresh_error: None,
+            refresh_failed_at: None,
+        }
+    }
+
+    pub fn insert(
+        conn: &PgConnection,
+        keyring: &Keyring,
+        token: &NewToken,
+    ) -> Result<Token, diesel::result::Error> {
+        let token = Token::from_new(token);
+
+        diesel::insert_into(tokens::table)
+            .values(&TokenRow::seal(&token, keyring)?)
+            .execute(conn)?;
+        Ok(token)
     }
 
+    /// Inserts the user's token for the service, or updates it if they already have one. An empty
+    /// refresh token keeps the one we have. Concurrent callbacks for the same user and service
+    /// end up in the same row instead of failing on the unique constraint.
+    pub fn upsert(
+        conn: &PgConnection,
+        keyring: &Keyring,
+        token: &NewToken,
+    ) -> Result<Token, diesel::result::Error> {
+        use self::schema::tokens::dsl::*;
+
+        conn.transaction(|| {
+            let new_token = Token::from_new(token);
+            // the update is a no-op that only locks the existing row and returns it, the tokens
+            // can't be merged in SQL since each row is sealed under its own data key
+            let row = diesel::insert_into(tokens)
+                .values(&TokenRow::seal(&new_token, keyring)?)
+                .on_conflict((user_id, service))
+                .do_update()
+                .set(service.eq(excluded(service)))
+                .get_result::<TokenRow>(conn)?;
+            if row.id == new_token.id {
+                return Ok(new_token);
+            }
+
+            let update = UpdateToken {
+                access_token: Some(token.access_token),
+                access_token_expiry: Some(token.access_token_expiry),
+                service_userid: Some(token.service_userid),
+                refresh_token: match token.refresh_token {
+                    "" => None,
+                    t => Some(t),
+                },
+                timezone: token.timezone,
+                utc_offset_ms: token.utc_offset_ms,
+                scopes: Some(token.scopes),
+            };
+            Token::apply(conn, keyring, row.open(keyring)?, &update)
+        })
+    }
+
+    /// Everything is sealed again under a new data key, even if only the expiry changed. New tokens
+    /// mean the provider is talking to us again, so a failed refresh is forgotten.
     pub fn update(
         conn: &PgConnection,
+        keyring: &Keyring,
         id: Uuid,
         update: &UpdateToken,
     ) -> Result<Token, diesel::result::Error> {
-        let _ = diesel::update(tokens::table.find(id))
-            .set(update)
+        conn.transaction(|| {
+            let current = Token::lock(conn, keyring, id)?;
+            Token::apply(conn, keyring, current, update)
+        })
+    }
+
+    /// Writes `update` over `current`, which the caller has locked.
+    fn apply(
+        conn: &PgConnection,
+        keyring: &Keyring,
+        current: Token,
+        update: &UpdateToken,
+    ) -> Result<Token, diesel::result::Error> {
+        let token = Token {
+            access_token: update
+                .access_token
+                .map_or(current.access_token, String::from),
+            access_token_expiry: update
+                .access_token_expiry
+                .map_or(current.access_token_expiry, |expiry| *expiry),
+            service_userid: update
+                .service_userid
+                .map_or(current.service_userid, String::from),
+            refresh_token: update
+                .refresh_token
+                .map_or(current.refresh_token, String::from),
+            timezone: update.timezone.map(String::from).or(current.timezone),
+            utc_offset_ms: update.utc_offset_ms.or(current.utc_offset_ms),
+            scopes: update.scopes.map_or(current.scopes, <[String]>::to_vec),
+            refresh_error: None,
+            refresh_failed_at: None,
+            ..current
+        };
+
+        diesel::update(tokens::table.find(token.id))
+            .set(&TokenRow::seal(&token, keyring)?)
             .execute(conn)?;
-        Token::find_one(conn, id)
+        Ok(token)
+    }
+
+    /// Remembers that refreshing failed, without touching the tokens themselves.
+    pub fn record_refresh_error(
+        conn: &PgConnection,
+        the_id: Uuid,
+        error: &str,
+    ) -> Result<usize, diesel::result::Error> {
+        use self::schema::tokens::dsl::*;
+
+        diesel::update(tokens.find(the_id))
+            .set((refresh_error.eq(error), refresh_failed_at.eq(Utc::now())))
+            .execute(conn)
+    }
+
+    pub fn delete(conn: &PgConnection, id: Uuid) -> Result<usize, diesel::result::Error> {
+        diesel::delete(tokens::table.find(id)).execute(conn)
+    }
+
+    /// Seals every token that's plaintext, or under a master key that isn't the current one,
+    /// with the current key. Returns how many were sealed.
+    pub fn encrypt_all(
+        conn: &PgConnection,
+        keyring: &Keyring,
+    ) -> Result<usize, diesel::result::Error> {
+        use self::schema::tokens::dsl::*;
+
+        let current = keyring.current_key_id();
+        let ids = tokens
+            .filter(key_id.is_null().or(key_id.ne(current)))
+            .select(id)
+            .load::<Uuid>(conn)?;
+
+        for the_id in &ids {
+            conn.transaction::<_, diesel::result::Error, _>(|| {
+                // locked, so a refresh that lands in between isn't overwritten with older tokens
+                let token = Token::lock(conn, keyring, *the_id)?;
+                diesel::update(tokens.find(the_id))
+                    .set(&TokenRow::seal(&token, keyring)?)
+                    .execute(conn)
+            })?;
+        }
+
+        Ok(ids.len())
     }
 }
 
@@ -82,18 +414,19 @@ pub struct CreateToken {
     pub access_token: String,
     pub access_token_expiry: DateTime<Utc>,
     pub refresh_token: String,
+    pub timezone: Option<String>,
+    pub utc_offset_ms: Option<i32>,
+    pub scopes: Vec<String>,
 }
 
 impl Message for CreateToken {
-    type Result = Result<db::Token, Error>;
+    type Result = Result<db::Token, AppError>;
 }
 
 impl Handler<CreateToken> for DbExecutor {
-    type Result = Result<db::Token, Error>;
+    type Result = Result<db::Token, AppError>;
 
     fn handle(&mut self, msg: CreateToken, _: &mut Self::Context) -> Self::Result {
-        use self::schema::tokens::dsl::*;
-
         let uuid = Uuid::new_v4();
         let new_token = db::NewToken {
             id: &uuid,
@@ -103,28 +436,14 @@ impl Handler<CreateToken> for DbExecutor {
             access_token: &msg.access_token,
             access_token_expiry: &msg.access_token_expiry,
             refresh_token: &msg.refresh_token,
+            timezone: msg.timezone.as_ref().map(String::as_str),
+            utc_offset_ms: msg.utc_offset_ms,
+            scopes: &msg.scopes,
         };
 
-        let conn: &PgConnection = &self.0.get().unwrap();
-
-        diesel::insert_into(tokens)
-            .values(&new_token)
-            .execute(conn)
-            .map_err(|e| {
-                error::ErrorInternalServerError(format!(
-                    "Error inserting token - {}",
-                    e.to_string()
-                ))
-            })?;
+        let conn: &PgConnection = &self.0.get()?;
 
-        let mut items = tokens
-            .filter(id.eq(&uuid))
-            .load::<db::Token>(conn)
-            .map_err(|e| {
-                error::ErrorInternalServerError(format!("Error loading token - {}", e.to_string()))
-            })?;
-
-        Ok(items.pop().unwrap())
+        Ok(Token::insert(conn, &self.1, &new_token)?)
     }
 }
 
@@ -135,18 +454,19 @@ pub struct UpsertToken {
     pub access_token: String,
     pub access_token_expiry: DateTime<Utc>,
     pub refresh_token: String,
+    pub timezone: Option<String>,
+    pub utc_offset_ms: Option<i32>,
+    pub scopes: Vec<String>,
 }
 
 impl Message for UpsertToken {
-    type Result = Result<db::Token, Error>;
+    type Result = Result<db::Token, AppError>;
 }
 
 impl Handler<UpsertToken> for DbExecutor {
-    type Result = Result<db::Token, Error>;
+    type Result = Result<db::Token, AppError>;
 
     fn handle(&mut self, msg: UpsertToken, _: &mut Self::Context) -> Self::Result {
-        use self::schema::tokens::dsl::*;
-
         let uuid = Uuid::new_v4();
         let new_token = db::NewToken {
             id: &uuid,
@@ -156,38 +476,13 @@ impl Handler<UpsertToken> for DbExecutor {
             access_token: &msg.access_token,
             access_token_expiry: &msg.access_token_expiry,
             refresh_token: &msg.refresh_token,
+            timezone: msg.timezone.as_ref().map(String::as_str),
+            utc_offset_ms: msg.utc_offset_ms,
+            scopes: &msg.scopes,
         };
 
-        let conn: &PgConnection = &self.0.get().unwrap();
-
-        diesel::insert_into(tokens)
-            .values(&new_token)
-            .on_conflict((user_id, service))
-            .do_update()
-            .set(&UpdateToken {
-                access_token: Some(new_token.access_token),
-                access_token_expiry: Some(new_token.access_token_expiry),
-                service_userid: Some(new_token.service_userid),
-                refresh_token: match new_token.refresh_token {
-                    "" => None,
-                    t => Some(t),
-                },
-            })
-            .execute(conn)
-            .map_err(|e| {
-                error::ErrorInternalServerError(format!(
-                    "Error inserting token - {}",
-                    e.to_string()
-                ))
-            })?;
-
-        let mut items = tokens
-            .filter(user_id.eq(&msg.user_id).and(service.eq(&msg.service)))
-            .load::<db::Token>(conn)
-            .map_err(|e| {
-                error::ErrorInternalServerError(format!("Error loading token - {}", e.to_string()))
-            })?;
+        let conn: &PgConnection = &self.0.get()?;
 
-        Ok(items.pop().unwrap())
+        Ok(Token::upsert(conn, &self.1, &new_token)?)
     }
 }
@@ -0,0 +1,182 @@
+//! The error type of everything that serves a request. Each kind of error has an HTTP status and
+//! a machine-readable code, which GraphQL errors carry as `extensions.code`.
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use juniper::{FieldError, FieldResult};
+use serde_json::json;
+use std::fmt::{self, Display};
+
+use crate::crypto::CryptoError;
+use crate::oauth::OAuthError;
+use crate::rate_limit::RateLimited;
+
+#[derive(Debug)]
+pub enum AppError {
+    Db(diesel::result::Error),
+    /// No connection to be had from the pool
+    Pool(diesel::r2d2::PoolError),
+    Crypto(CryptoError),
+    OAuth(OAuthError),
+    /// A provider's API failed
+    Provider(reqwest::Error),
+    Queue(String),
+    RateLimited(RateLimited),
+    /// Not logged in, or with credentials that don't check out
+    Unauthenticated(String),
+    Forbidden(String),
+    NotFound(String),
+    BadRequest(String),
+    Conflict(String),
+}
+
+impl AppError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::Db(diesel::result::Error::NotFound) => "NOT_FOUND",
+            AppError::Db(_) => "DATABASE_ERROR",
+            AppError::Pool(_) => "DATABASE_UNAVAILABLE",
+            AppError::Crypto(_) => "CRYPTO_ERROR",
+            AppError::OAuth(_) => "OAUTH_ERROR",
+            AppError::Provider(_) => "PROVIDER_ERROR",
+            AppError::Queue(_) => "QUEUE_ERROR",
+            AppError::RateLimited(_) => "RATE_LIMITED",
+            AppError::Unauthenticated(_) => "UNAUTHENTICATED",
+            AppError::Forbidden(_) => "FORBIDDEN",
+            AppError::NotFound(_) => "NOT_FOUND",
+            AppError::BadRequest(_) => "BAD_REQUEST",
+            AppError::Conflict(_) => "CONFLICT",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AppError::Db(diesel::result::Error::NotFound) => StatusCode::NOT_FOUND,
+            AppError::Db(_) | AppError::Crypto(_) | AppError::Queue(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+            AppError::Pool(_) => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::OAuth(_) | AppError::Provider(_) => StatusCode::BAD_GATEWAY,
+            AppError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            AppError::Unauthenticated(_) => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden(_) => StatusCode::FORBIDDEN,
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            AppError::Conflict(_) => StatusCode::CONFLICT,
+        }
+    }
+
+    /// What the client gets to see. Server errors can have connection strings and provider
+    /// responses in them, so the client only gets a generic message for those.
+    fn public_message(&self) -> String {
+        if self.status().is_server_error() {
+            match self {
+                AppError::OAuth(_) | AppError::Provider(_) => "Provider error".to_owned(),
+                _ => "Internal server error".to_owned(),
+            }
+        } else {
+            self.to_string()
+        }
+    }
+
+    /// Server errors are logged here, since the client doesn't get to see what went wrong.
+    fn log(&self) {
+        if self.status().is_server_error() {
+            error!("{}", self);
+        }
+    }
+
+    pub fn into_field_error(self) -> FieldError {
+        self.log();
+        FieldError::new(
+            self.public_message(),
+            graphql_value!({ "code": (self.code()) }),
+        )
+    }
+}
+
+impl Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AppError::Db(diesel::result::Error::NotFound) => write!(f, "Not found"),
+            AppError::Db(e) => write!(f, "database error: {}", e),
+            AppError::Pool(e) => write!(f, "no database connection: {}", e),
+            AppError::Crypto(e) => write!(f, "{}", e),
+            AppError::OAuth(e) => write!(f, "{}", e),
+            AppError::Provider(e) => write!(f, "{}", e),
+            AppError::Queue(e) => write!(f, "queue error: {}", e),
+            AppError::RateLimited(e) => write!(f, "{}", e),
+            AppError::Unauthenticated(e)
+            | AppError::Forbidden(e)
+            | AppError::NotFound(e)
+            | AppError::BadRequest(e)
+            | AppError::Conflict(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for AppError {}
+
+impl ResponseError for AppError {
+    fn error_response(&self) -> HttpResponse {
+        self.log();
+        HttpResponse::build(self.status()).json(json!({
+            "error": {
+                "code": self.code(),
+                "message": self.public_message(),
+            }
+        }))
+    }
+}
+
+impl From<diesel::result::Error> for AppError {
+    fn from(e: diesel::result::Error) -> Self {
+        AppError::Db(e)
+    }
+}
+
+impl From<diesel::r2d2::PoolError> for AppError {
+    fn from(e: diesel::r2d2::PoolError) -> Self {
+        AppError::Pool(e)
+    }
+}
+
+impl From<CryptoError> for AppError {
+    fn from(e: CryptoError) -> Self {
+        AppError::Crypto(e)
+    }
+}
+
+impl From<OAuthError> for AppError {
+    fn from(e: OAuthError) -> Self {
+        AppError::OAuth(e)
+    }
+}
+
+impl From<reqwest::Error> for AppError {
+    fn from(e: reqwest::Error) -> Self {
+        AppError::Provider(e)
+    }
+}
+
+impl From<redis::RedisError> for AppError {
+    fn from(e: redis::RedisError) -> Self {
+        AppError::Queue(e.to_string())
+    }
+}
+
+impl From<RateLimited> for AppError {
+    fn from(e: RateLimited) -> Self {
+        AppError::RateLimited(e)
+    }
+}
+
+/// `?` makes a `FieldError` out of anything `Display`, but without a code. Resolvers go through
+/// `AppError` with this instead.
+pub trait FieldResultExt<T> {
+    fn field_err(self) -> FieldResult<T>;
+}
+
+impl<T, E: Into<AppError>> FieldResultExt<T> for Result<T, E> {
+    fn field_err(self) -> FieldResult<T> {
+        self.map_err(|e| e.into().into_field_error())
+    }
+}
@@ -1,15 +1,22 @@
 use crate::{
-    db::{self, User},
+    crypto::Keyring,
+    db::{self, AccessToken, AccessTokenScope, Grant, GrantMetric, User},
+    error::{AppError, FieldResultExt},
+    oauth::OAuth,
     queue::Queue,
     AppState,
 };
 use actix::prelude::*;
 use actix_web::middleware::identity::RequestIdentity;
-use actix_web::{AsyncResponder, Error, FutureResponse, HttpMessage, HttpRequest, HttpResponse};
+use actix_web::{
+    http::header, AsyncResponder, Error, FutureResponse, HttpMessage, HttpRequest, HttpResponse,
+};
+use chrono::{DateTime, Utc};
 use futures::future::Future;
 use juniper::http::graphiql::graphiql_source;
 use juniper::http::GraphQLRequest;
-use juniper::Context as JuniperContext;
+use juniper::{Context as JuniperContext, FieldResult};
+use std::sync::Arc;
 use uuid::Uuid;
 
 pub mod schema;
@@ -18,24 +25,159 @@ pub mod schema;
 pub struct GraphQLData {
     req: GraphQLRequest,
     user_id: Option<Uuid>,
+    /// A personal access token, which takes precedence over the cookie's `user_id`
+    bearer: Option<String>,
 }
 
 pub struct Context {
     pub conn: db::Conn,
     pub user: Option<User>,
+    /// `None` for the login cookie, which can do anything
+    pub scope: Option<AccessTokenScope>,
     pub producer: Queue,
+    pub oauth: Arc<OAuth>,
+    pub keyring: Arc<Keyring>,
 }
 
 impl JuniperContext for Context {}
 
+/// Why the viewer gets to see a user's data.
+#[derive(Debug, Clone)]
+pub enum Access {
+    Owner,
+    Admin,
+    /// Only what the owner's unexpired grants to the viewer share
+    Grant(Vec<Grant>),
+}
+
+impl Access {
+    /// Fails unless the viewer may see everything, like the owner's settings and connections.
+    pub fn full(&self) -> FieldResult<()> {
+        match self {
+            Access::Owner | Access::Admin => Ok(()),
+            Access::Grant(_) => {
+                Err(AppError::Forbidden("Not authorized".to_owned()).into_field_error())
+            }
+        }
+    }
+
+    /// The part of `start` to `end` of `metric` the viewer may see. Grants' windows aren't merged,
+    /// so with several the one that shows the most of it wins.
+    pub fn window(
+        &self,
+        metric: GrantMetric,
+        (start, end): (DateTime<Utc>, DateTime<Utc>),
+    ) -> FieldResult<(DateTime<Utc>, DateTime<Utc>)> {
+        let grants = match self {
+            Access::Owner | Access::Admin => return Ok((start, end)),
+            Access::Grant(grants) => grants,
+        };
+
+        let grants = grants
+            .iter()
+            .filter(|grant| grant.includes(metric))
+            .collect::<Vec<_>>();
+        if grants.is_empty() {
+            return Err(
+                AppError::Forbidden(format!("{} isn't shared with you", metric.as_str()))
+                    .into_field_error(),
+            );
+        }
+
+        grants
+            .iter()
+            .map(|grant| {
+                (
+                    grant.start_time.map_or(start, |s| s.max(start)),
+                    grant.end_time.map_or(end, |e| e.min(end)),
+                )
+            })
+            .filter(|(start, end)| start < end)
+            .max_by_key(|(start, end)| *end - *start)
+            .ok_or_else(|| {
+                AppError::Forbidden(format!(
+                    "{} isn't shared with you for that period",
+                    metric.as_str()
+                ))
+                .into_field_error()
+            })
+    }
+}
+
+/// A user as the viewer is allowed to see them. Only `Context::view` makes these, so anything
+/// reachable from one has been authorized.
+pub struct UserView {
+    pub user: User,
+    pub access: Access,
+}
+
+/// A sleep log, with the part of it whose stages the viewer may see. Only `UserView`'s `sleep`
+/// makes these.
+pub struct SleepView {
+    pub sleep: db::Sleep,
+    /// `None` when none of it is shared
+    pub window: Option<(DateTime<Utc>, DateTime<Utc>)>,
+}
+
 impl Context {
-    pub fn new(conn: db::Conn, user: Option<User>, producer: Queue) -> Context {
+    pub fn new(
+        conn: db::Conn,
+        user: Option<User>,
+        scope: Option<AccessTokenScope>,
+        producer: Queue,
+        oauth: Arc<OAuth>,
+        keyring: Arc<Keyring>,
+    ) -> Context {
         Context {
             conn,
             user,
+            scope,
             producer,
+            oauth,
+            keyring,
+        }
+    }
+
+    pub fn viewer(&self) -> FieldResult<&User> {
+        self.user
+            .as_ref()
+            .ok_or_else(|| AppError::Unauthenticated("Not logged in".to_owned()).into_field_error())
+    }
+
+    /// The viewer, if they may change things. Every mutation goes through this.
+    pub fn writer(&self) -> FieldResult<&User> {
+        match self.scope {
+            Some(AccessTokenScope::Read) => {
+                Err(AppError::Forbidden("Access token is read-only".to_owned()).into_field_error())
+            }
+            _ => self.viewer(),
+        }
+    }
+
+    /// Whether the viewer may read the data of `owner_id`, and why.
+    pub fn authorize(&self, owner_id: &Uuid) -> FieldResult<Access> {
+        let viewer = self.viewer()?;
+        if viewer.id == *owner_id {
+            return Ok(Access::Owner);
+        } else if viewer.is_admin {
+            return Ok(Access::Admin);
+        }
+
+        let grants = Grant::active_between(&self.conn, owner_id, &viewer.id).field_err()?;
+        if grants.is_empty() {
+            Err(AppError::Forbidden("Not authorized".to_owned()).into_field_error())
+        } else {
+            Ok(Access::Grant(grants))
         }
     }
+
+    pub fn view(&self, user: User) -> FieldResult<UserView> {
+        let access = self.authorize(&user.id)?;
+        Ok(UserView {
+            user,
+            access,
+        })
+    }
 }
 
 impl Message for GraphQLData {
@@ -46,6 +188,8 @@ pub struct GraphQLExecutor {
     schema: std::sync::Arc<schema::Schema>,
     pool: db::Pool,
     producer: Queue,
+    oauth: Arc<OAuth>,
+    keyring: Arc<Keyring>,
 }
 
 impl GraphQLExecutor {
@@ -53,11 +197,15 @@ impl GraphQLExecutor {
         schema: std::sync::Arc<schema::Schema>,
         pool: db::Pool,
         producer: Queue,
+        oauth: Arc<OAuth>,
+        keyring: Arc<Keyring>,
     ) -> GraphQLExecutor {
         GraphQLExecutor {
             schema,
             pool,
             producer,
+            oauth,
+            keyring,
         }
     }
 }
@@ -70,9 +218,29 @@ impl Handler<GraphQLData> for GraphQLExecutor {
     type Result = Result<String, Error>;
 
     fn handle(&mut self, msg: GraphQLData, _: &mut Self::Context) -> Self::Result {
-        let conn = self.pool.get().unwrap();
-        let user = msg.user_id.and_then(|id| User::find_one(&conn, &id).ok());
-        let context = Context::new(db::Conn(conn), user, self.producer.clone());
+        let conn = self.pool.get().map_err(AppError::from)?;
+        let (user, scope) = match msg.bearer {
+            Some(bearer) => {
+                let token = AccessToken::authenticate(&conn, &bearer)
+                    .map_err(|_| AppError::Unauthenticated("Invalid access token".to_owned()))?;
+                (
+                    User::find_one(&conn, &token.user_id).ok(),
+                    Some(token.scope().unwrap_or(AccessTokenScope::Read)),
+                )
+            }
+            None => (
+                msg.user_id.and_then(|id| User::find_one(&conn, &id).ok()),
+                None,
+            ),
+        };
+        let context = Context::new(
+            db::Conn(conn),
+            user,
+            scope,
+            self.producer.clone(),
+            self.oauth.clone(),
+            self.keyring.clone(),
+        );
 
         let res = msg.req.execute(&self.schema, &context);
         let res_text = serde_json::to_string(&res)?;
@@ -91,6 +259,12 @@ pub fn graphql(req: &HttpRequest<AppState>) -> FutureResponse<HttpResponse> {
     let req = req.clone();
     let executor = req.state().graphql.clone();
     let user_id = req.identity().and_then(|id| Uuid::parse_str(&id).ok());
+    let bearer = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| value.starts_with("Bearer "))
+        .map(|value| value["Bearer ".len()..].trim().to_string());
 
     req.json()
         .from_err()
@@ -99,11 +273,12 @@ pub fn graphql(req: &HttpRequest<AppState>) -> FutureResponse<HttpResponse> {
                 .send(GraphQLData {
                     req,
                     user_id,
+                    bearer,
                 })
                 .from_err()
                 .and_then(|res| match res {
                     Ok(data) => Ok(HttpResponse::Ok().body(data)),
-                    Err(_) => Ok(HttpResponse::InternalServerError().into()),
+                    Err(e) => Err(e),
                 })
         })
         .responder()
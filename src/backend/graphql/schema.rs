@@ -1,138 +1,811 @@
 use juniper::{FieldResult, RootNode};
 use uuid::Uuid;
 
-use super::Context;
-use crate::db::{self, Object};
-use crate::providers::fitbit::IntradayMetric;
+use super::{Context, SleepView, UserView};
+use crate::db::{self, GrantMetric, Measurement, Object};
+use crate::error::{AppError, FieldResultExt};
+use crate::providers::fitbit::{self, IntradayMetric};
 use crate::queue::{QueueAction, QueueActionParams};
 use chrono::{DateTime, NaiveDate, Utc};
+use chrono_tz::{Tz, UTC};
+use diesel::{Connection, OptionalExtension};
+
+/// `start_time` (default: start of today) to `end_time` (default: now).
+fn period(
+    start_time: Option<DateTime<Utc>>,
+    end_time: Option<DateTime<Utc>>,
+) -> (DateTime<Utc>, DateTime<Utc>) {
+    (
+        start_time.unwrap_or_else(|| Utc::today().and_hms(0, 0, 0)),
+        end_time.unwrap_or_else(Utc::now),
+    )
+}
+
+/// Records the job for `action` and pushes it for a worker to pick up.
+fn enqueue(context: &Context, action: QueueAction) -> FieldResult<db::Job> {
+    db::Job::enqueue(&context.conn, &context.producer, action).field_err()
+}
+
+/// A user's measurements of `metric` over `period`, as far as the viewer may see them, newest
+/// first.
+fn measurements<T: Measurement>(
+    context: &Context,
+    user: &UserView,
+    metric: GrantMetric,
+    start_time: Option<DateTime<Utc>>,
+    end_time: Option<DateTime<Utc>>,
+    only_populated: bool,
+) -> FieldResult<Vec<T>> {
+    let (start, end) = user.access.window(metric, period(start_time, end_time))?;
+
+    let measurements = T::for_period(&context.conn, &user.user.id, &start, &end)
+        .field_err()?
+        .into_iter()
+        .filter(|m| !only_populated || m.is_populated())
+        .collect();
+
+    Ok(measurements)
+}
+
+/// Where the user's days start and end. Fitbit is the only service that knows, so without a fitbit
+/// token days are UTC.
+fn user_tz(context: &Context, user_id: &Uuid) -> Tz {
+    db::Token::find_by_uid_service(&context.conn, &context.keyring, user_id, "fitbit")
+        .ok()
+        .and_then(|token| fitbit::local_tz(&token).ok())
+        .unwrap_or(UTC)
+}
 
 pub struct QueryRoot;
 
 graphql_object!(QueryRoot: Context |&self| {
-    field user(&executor, id: Option<Uuid>) -> FieldResult<Option<db::User>> {
-        let conn = &executor.context().conn;
+    field user(&executor, id: Option<Uuid>) -> FieldResult<Option<UserView>> {
+        let context = executor.context();
         let user = match id {
-            Some(id) => db::User::find_one(conn, &id).ok(),
-            None => executor.context().user.clone()
+            Some(id) => db::User::find_one(&context.conn, &id).optional().field_err()?,
+            None => context.user.clone()
         };
 
-        Ok(user)
+        // someone the viewer can't see looks the same as someone who doesn't exist
+        Ok(user.and_then(|user| context.view(user).ok()))
+    }
+
+    field job(&executor, id: Uuid) -> FieldResult<Option<db::Job>> {
+        let context = executor.context();
+        context.viewer()?;
+        let job = db::Job::find_one(&context.conn, &id)
+            .optional()
+            .field_err()?
+            // jobs are about the owner's connections, which grants don't share
+            .filter(|job| {
+                context.authorize(&job.user_id).and_then(|access| access.full()).is_ok()
+            });
+
+        Ok(job)
+    }
+
+    field shared_with_me(&executor) -> FieldResult<Vec<db::Grant>> {
+        let context = executor.context();
+        let grants = db::Grant::active_for_grantee(&context.conn, &context.viewer()?.id).field_err()?;
+
+        Ok(grants)
     }
 });
 
-graphql_object!(db::User: Context as "User" |&self| {
-    field steps(&executor, start_time: Option<DateTime<Utc>>, end_time: Option<DateTime<Utc>>, only_populated = true: bool) -> FieldResult<Vec<db::Step>> {
-        let conn = &executor.context().conn;
-        let steps = db::Step::for_period(conn, &self.id, &start_time.unwrap_or_else(|| Utc::today().and_hms(0, 0, 0)), &end_time.unwrap_or_else(Utc::now)).unwrap_or_else(|_| vec![])
-            .into_iter()
-            .filter(|s| !only_populated || s.count > 0)
-            .collect();
+graphql_object!(UserView: Context as "User" |&self| {
+    description: "A user, as far as the viewer may see them"
 
-        Ok(steps)
+    field steps(&executor, start_time: Option<DateTime<Utc>>, end_time: Option<DateTime<Utc>>, only_populated = true: bool) -> FieldResult<Vec<db::Step>> {
+        measurements(executor.context(), self, GrantMetric::Step, start_time, end_time, only_populated)
     }
 
     field floors(&executor, start_time: Option<DateTime<Utc>>, end_time: Option<DateTime<Utc>>, only_populated = true: bool) -> FieldResult<Vec<db::Floor>> {
+        measurements(executor.context(), self, GrantMetric::Floor, start_time, end_time, only_populated)
+    }
+
+    field distances(&executor, start_time: Option<DateTime<Utc>>, end_time: Option<DateTime<Utc>>, only_populated = true: bool) -> FieldResult<Vec<db::Distance>> {
+        measurements(executor.context(), self, GrantMetric::Distance, start_time, end_time, only_populated)
+    }
+
+    field elevations(&executor, start_time: Option<DateTime<Utc>>, end_time: Option<DateTime<Utc>>, only_populated = true: bool) -> FieldResult<Vec<db::Elevation>> {
+        measurements(executor.context(), self, GrantMetric::Elevation, start_time, end_time, only_populated)
+    }
+
+    field calories(&executor, start_time: Option<DateTime<Utc>>, end_time: Option<DateTime<Utc>>, only_populated = true: bool) -> FieldResult<Vec<db::Calorie>> {
+        measurements(executor.context(), self, GrantMetric::Calorie, start_time, end_time, only_populated)
+    }
+
+    field heart_rates(&executor, start_time: Option<DateTime<Utc>>, end_time: Option<DateTime<Utc>>, only_populated = true: bool) -> FieldResult<Vec<db::HeartRate>> {
+        measurements(executor.context(), self, GrantMetric::HeartRate, start_time, end_time, only_populated)
+    }
+
+    field heart_rates_per_minute(&executor, start_time: Option<DateTime<Utc>>, end_time: Option<DateTime<Utc>>, only_populated = true: bool) -> FieldResult<Vec<db::HeartRateMinute>> {
+        measurements(executor.context(), self, GrantMetric::HeartRate, start_time, end_time, only_populated)
+    }
+
+    field resting_heart_rates(&executor, start_time: Option<DateTime<Utc>>, end_time: Option<DateTime<Utc>>) -> FieldResult<Vec<db::RestingHeartRate>> {
+        measurements(executor.context(), self, GrantMetric::HeartRate, start_time, end_time, false)
+    }
+
+    field aggregate(&executor, metric: IntradayMetric, start_time: Option<DateTime<Utc>>, end_time: Option<DateTime<Utc>>, bucket = ("1h".to_owned()): String, function = (db::AggregateFunction::Sum): db::AggregateFunction, timezone: Option<String>) -> FieldResult<Vec<db::AggregatePoint>> {
+        let context = executor.context();
+        let (start, end) = self.access.window(metric.grant_metric(), period(start_time, end_time))?;
+        let bucket = bucket.parse::<db::Bucket>().map_err(AppError::BadRequest).field_err()?;
+        let tz = match timezone {
+            Some(tz) => tz.parse::<Tz>().map_err(AppError::BadRequest).field_err()?,
+            None => user_tz(context, &self.user.id)
+        };
+
+        let points = db::aggregate(&context.conn, metric.table(), &self.user.id, (&start, &end), bucket, function, tz).field_err()?;
+
+        Ok(points)
+    }
+
+    field heart_rate_zones(&executor, start_time: Option<DateTime<Utc>>, end_time: Option<DateTime<Utc>>) -> FieldResult<Vec<db::HeartRateZone>> {
         let conn = &executor.context().conn;
-        let floors = db::Floor::for_period(conn, &self.id, &start_time.unwrap_or_else(|| Utc::today().and_hms(0, 0, 0)), &end_time.unwrap_or_else(Utc::now)).unwrap_or_else(|_| vec![])
-            .into_iter()
-            .filter(|f| !only_populated || f.count > 0)
-            .collect();
+        let (start, end) = self.access.window(GrantMetric::HeartRate, period(start_time, end_time))?;
+        let zones = db::HeartRateZone::for_period(conn, &self.user.id, &start, &end).field_err()?;
 
-        Ok(floors)
+        Ok(zones)
     }
 
-    field distances(&executor, start_time: Option<DateTime<Utc>>, end_time: Option<DateTime<Utc>>, only_populated = true: bool) -> FieldResult<Vec<db::Distance>> {
+    field sleep(&executor, start_time: Option<DateTime<Utc>>, end_time: Option<DateTime<Utc>>) -> FieldResult<Vec<SleepView>> {
         let conn = &executor.context().conn;
-        let distances = db::Distance::for_period(conn, &self.id, &start_time.unwrap_or_else(|| Utc::today().and_hms(0, 0, 0)), &end_time.unwrap_or_else(Utc::now)).unwrap_or_else(|_| vec![])
+        let (start, end) = self.access.window(GrantMetric::Sleep, period(start_time, end_time))?;
+        let sleeps = db::Sleep::for_period(conn, &self.user.id, &start, &end)
+            .field_err()?
             .into_iter()
-            .filter(|d| !only_populated || d.count > 0.0)
+            .map(|sleep| SleepView {
+                // a sleep that straddles the edge of a grant only shows the stages inside it
+                window: self.access.window(GrantMetric::Sleep, (sleep.start_time, sleep.end_time)).ok(),
+                sleep,
+            })
             .collect();
 
-        Ok(distances)
+        Ok(sleeps)
     }
 
-    field elevations(&executor, start_time: Option<DateTime<Utc>>, end_time: Option<DateTime<Utc>>, only_populated = true: bool) -> FieldResult<Vec<db::Elevation>> {
+    field weights(&executor, start_time: Option<DateTime<Utc>>, end_time: Option<DateTime<Utc>>) -> FieldResult<Vec<db::BodyMeasurement>> {
         let conn = &executor.context().conn;
-        let elevations = db::Elevation::for_period(conn, &self.id, &start_time.unwrap_or_else(|| Utc::today().and_hms(0, 0, 0)), &end_time.unwrap_or_else(Utc::now)).unwrap_or_else(|_| vec![])
-            .into_iter()
-            .filter(|e| !only_populated || e.count > 0.0)
-            .collect();
+        let (start, end) = self.access.window(GrantMetric::Weight, period(start_time, end_time))?;
+        let weights = db::BodyMeasurement::for_period(conn, &self.user.id, db::BodyMeasurementKind::Weight, &start, &end).field_err()?;
 
-        Ok(elevations)
+        Ok(weights)
     }
 
-    field calories(&executor, start_time: Option<DateTime<Utc>>, end_time: Option<DateTime<Utc>>, only_populated = true: bool) -> FieldResult<Vec<db::Calorie>> {
+    field body_fat(&executor, start_time: Option<DateTime<Utc>>, end_time: Option<DateTime<Utc>>) -> FieldResult<Vec<db::BodyMeasurement>> {
         let conn = &executor.context().conn;
-        let calories = db::Calorie::for_period(conn, &self.id, &start_time.unwrap_or_else(|| Utc::today().and_hms(0, 0, 0)), &end_time.unwrap_or_else(Utc::now)).unwrap_or_else(|_| vec![])
-            .into_iter()
-            .filter(|c| !only_populated || c.count > 0.0)
-            .collect();
+        let (start, end) = self.access.window(GrantMetric::BodyFat, period(start_time, end_time))?;
+        let body_fat = db::BodyMeasurement::for_period(conn, &self.user.id, db::BodyMeasurementKind::Fat, &start, &end).field_err()?;
 
-        Ok(calories)
+        Ok(body_fat)
     }
 
     field moods(&executor, start_time: Option<DateTime<Utc>>, end_time: Option<DateTime<Utc>>) -> FieldResult<Vec<db::Mood>> {
         let conn = &executor.context().conn;
-        let moods = db::Mood::for_period(conn, &self.id, &start_time.unwrap_or_else(|| Utc::today().and_hms(0, 0, 0)), &end_time.unwrap_or_else(Utc::now)).unwrap_or_else(|_| vec![])
-            .into_iter()
-            .collect();
+        let (start, end) = self.access.window(GrantMetric::Mood, period(start_time, end_time))?;
+        let moods = db::Mood::for_period(conn, &self.user.id, &start, &end).field_err()?;
 
         Ok(moods)
     }
 
+    field connections(&executor) -> FieldResult<Vec<db::Token>> {
+        self.access.full()?;
+        let context = executor.context();
+        let tokens = db::Token::for_user(&context.conn, &context.keyring, &self.user.id)
+            .field_err()?;
+
+        Ok(tokens)
+    }
+
+    field identities(&executor) -> FieldResult<Vec<db::Identity>> {
+        self.access.full()?;
+        let conn = &executor.context().conn;
+        let identities = db::Identity::for_user(conn, &self.user.id).field_err()?;
+
+        Ok(identities)
+    }
+
+    field access_tokens(&executor) -> FieldResult<Vec<db::AccessToken>> {
+        self.access.full()?;
+        let conn = &executor.context().conn;
+        let tokens = db::AccessToken::for_user(conn, &self.user.id).field_err()?;
+
+        Ok(tokens)
+    }
+
+    field grants(&executor) -> FieldResult<Vec<db::Grant>> {
+        self.access.full()?;
+        let conn = &executor.context().conn;
+        let grants = db::Grant::for_owner(conn, &self.user.id).field_err()?;
+
+        Ok(grants)
+    }
+
+    field sync_settings(&executor) -> FieldResult<Vec<db::SyncSetting>> {
+        self.access.full()?;
+        let conn = &executor.context().conn;
+        let settings = db::SyncSetting::for_user(conn, &self.user.id).field_err()?;
+
+        Ok(settings)
+    }
+
+    field sync_cursors(&executor) -> FieldResult<Vec<db::SyncCursor>> {
+        self.access.full()?;
+        let conn = &executor.context().conn;
+        let cursors = db::SyncCursor::for_user(conn, &self.user.id).field_err()?;
+
+        Ok(cursors)
+    }
+
+    field jobs(&executor, limit = 20: i32) -> FieldResult<Vec<db::Job>> {
+        self.access.full()?;
+        let conn = &executor.context().conn;
+        let jobs = db::Job::for_user(conn, &self.user.id, i64::from(limit)).field_err()?;
+
+        Ok(jobs)
+    }
+
     field email() -> &str {
+        &self.user.email
+    }
+
+    field id() -> &Uuid {
+        &self.user.id
+    }
+
+    field is_admin() -> bool {
+        self.user.is_admin
+    }
+});
+
+graphql_object!(SleepView: Context as "Sleep" |&self| {
+    description: "A single sleep log"
+
+    field start_time() -> &DateTime<Utc> {
+        &self.sleep.start_time
+    }
+
+    field end_time() -> &DateTime<Utc> {
+        &self.sleep.end_time
+    }
+
+    field source() -> &str {
+        &self.sleep.source
+    }
+
+    // i64 isn't a GraphQL scalar
+    field log_id() -> String {
+        self.sleep.log_id.to_string()
+    }
+
+    field is_main_sleep() -> bool {
+        self.sleep.is_main_sleep
+    }
+
+    field efficiency() -> i32 {
+        self.sleep.efficiency
+    }
+
+    field minutes_asleep() -> i32 {
+        self.sleep.minutes_asleep
+    }
+
+    field minutes_awake() -> i32 {
+        self.sleep.minutes_awake
+    }
+
+    field time_in_bed() -> i32 {
+        self.sleep.time_in_bed
+    }
+
+    field stages(&executor) -> FieldResult<Vec<db::SleepStage>> {
+        let (start, end) = match self.window {
+            Some(window) => window,
+            None => return Ok(vec![]),
+        };
+        let conn = &executor.context().conn;
+        let stages = db::SleepStage::for_period(conn, &self.sleep.user_id, &start, &end).field_err()?;
+
+        Ok(stages)
+    }
+});
+
+graphql_object!(db::Job: Context as "Job" |&self| {
+    description: "A queued ingest and what became of it"
+
+    field id() -> &Uuid {
+        &self.id
+    }
+
+    field status() -> FieldResult<db::JobStatus> {
+        Ok(self.status.parse::<db::JobStatus>()?)
+    }
+
+    // QueueActionParams as json
+    field params() -> &str {
+        &self.params
+    }
+
+    field attempts() -> i32 {
+        self.attempts
+    }
+
+    field error() -> &Option<String> {
+        &self.error
+    }
+
+    field created_at() -> &DateTime<Utc> {
+        &self.created_at
+    }
+
+    field started_at() -> &Option<DateTime<Utc>> {
+        &self.started_at
+    }
+
+    field finished_at() -> &Option<DateTime<Utc>> {
+        &self.finished_at
+    }
+
+    field parent_id() -> &Option<Uuid> {
+        &self.parent_id
+    }
+
+    field children(&executor) -> FieldResult<Vec<db::Job>> {
+        let children = db::Job::children(&executor.context().conn, &self.id).field_err()?;
+
+        Ok(children)
+    }
+
+    field progress(&executor) -> FieldResult<db::JobProgress> {
+        let progress = db::Job::progress(&executor.context().conn, &self.id).field_err()?;
+
+        Ok(progress)
+    }
+});
+
+graphql_object!(db::Token: Context as "Connection" |&self| {
+    description: "A connected service, without its tokens"
+
+    field service() -> &str {
+        &self.service
+    }
+
+    field service_userid() -> &str {
+        &self.service_userid
+    }
+
+    field scopes() -> &Vec<String> {
+        &self.scopes
+    }
+
+    field access_token_expiry() -> &DateTime<Utc> {
+        &self.access_token_expiry
+    }
+
+    // the user has to connect the service again
+    field refresh_failed() -> bool {
+        self.refresh_error.is_some()
+    }
+
+    field refresh_error() -> &Option<String> {
+        &self.refresh_error
+    }
+
+    field refresh_failed_at() -> &Option<DateTime<Utc>> {
+        &self.refresh_failed_at
+    }
+});
+
+graphql_object!(db::Identity: Context as "Identity" |&self| {
+    description: "An account the user can log in with"
+
+    field provider() -> &str {
+        &self.provider
+    }
+
+    field subject() -> &str {
+        &self.subject
+    }
+
+    field email() -> &Option<String> {
         &self.email
     }
 
+    field created_at() -> &DateTime<Utc> {
+        &self.created_at
+    }
+});
+
+graphql_object!(db::AccessToken: Context as "AccessToken" |&self| {
+    description: "A personal access token, for calling the API with an Authorization: Bearer header"
+
     field id() -> &Uuid {
         &self.id
     }
+
+    field name() -> &str {
+        &self.name
+    }
+
+    // the first few characters of the token
+    field prefix() -> &str {
+        &self.prefix
+    }
+
+    field scope() -> FieldResult<db::AccessTokenScope> {
+        Ok(self.scope.parse::<db::AccessTokenScope>()?)
+    }
+
+    field created_at() -> &DateTime<Utc> {
+        &self.created_at
+    }
+
+    field expires_at() -> &Option<DateTime<Utc>> {
+        &self.expires_at
+    }
+
+    field last_used_at() -> &Option<DateTime<Utc>> {
+        &self.last_used_at
+    }
+});
+
+/// An access token just after it was created, the only time the token itself is known.
+pub struct CreatedAccessToken {
+    access_token: db::AccessToken,
+    token: String,
+}
+
+graphql_object!(CreatedAccessToken: Context |&self| {
+    field access_token() -> &db::AccessToken {
+        &self.access_token
+    }
+
+    field token() -> &str {
+        &self.token
+    }
+});
+
+graphql_object!(db::Grant: Context as "Grant" |&self| {
+    description: "Read-only access to some of a user's metrics for another user"
+
+    field id() -> &Uuid {
+        &self.id
+    }
+
+    // null for a grantee once the grant has expired
+    field owner(&executor) -> FieldResult<Option<UserView>> {
+        let context = executor.context();
+        let owner = db::User::find_one(&context.conn, &self.owner_id).field_err()?;
+
+        Ok(context.view(owner).ok())
+    }
+
+    field grantee_email(&executor) -> FieldResult<String> {
+        let grantee = db::User::find_one(&executor.context().conn, &self.grantee_id).field_err()?;
+
+        Ok(grantee.email)
+    }
+
+    field metrics() -> Vec<GrantMetric> {
+        self.metrics.iter().filter_map(|m| m.parse().ok()).collect()
+    }
+
+    field start_time() -> &Option<DateTime<Utc>> {
+        &self.start_time
+    }
+
+    field end_time() -> &Option<DateTime<Utc>> {
+        &self.end_time
+    }
+
+    field expires_at() -> &Option<DateTime<Utc>> {
+        &self.expires_at
+    }
+
+    field created_at() -> &DateTime<Utc> {
+        &self.created_at
+    }
+});
+
+graphql_object!(db::SyncSetting: Context as "SyncSetting" |&self| {
+    description: "How often a connected service is synced automatically"
+
+    field service() -> &str {
+        &self.service
+    }
+
+    field interval_minutes() -> i32 {
+        self.interval_minutes
+    }
+
+    field metrics() -> Vec<IntradayMetric> {
+        self.metrics.iter().filter_map(|m| m.parse().ok()).collect()
+    }
+
+    field enabled() -> bool {
+        self.enabled
+    }
+
+    field last_scheduled_at() -> &Option<DateTime<Utc>> {
+        &self.last_scheduled_at
+    }
+});
+
+graphql_object!(db::SyncCursor: Context as "SyncCursor" |&self| {
+    description: "The last day of a metric that was synced completely"
+
+    field service() -> &str {
+        &self.service
+    }
+
+    field metric() -> Option<IntradayMetric> {
+        self.metric.parse().ok()
+    }
+
+    field synced_through() -> &NaiveDate {
+        &self.synced_through
+    }
+
+    field updated_at() -> &DateTime<Utc> {
+        &self.updated_at
+    }
 });
 
 pub struct MutationRoot;
 
 graphql_object!(MutationRoot: Context |&self| {
-    field ingest_intraday(&executor, service: String, measurement: IntradayMetric, date: Option<NaiveDate>, num_days = 1: i32) -> FieldResult<bool> {
-        let producer = &executor.context().producer;
-        let user_id = executor.context().user.clone().ok_or_else(|| "Not logged in".to_owned())?.id;
+    field ingest_intraday(&executor, service: String, measurement: IntradayMetric, date: Option<NaiveDate>, num_days = 1: i32) -> FieldResult<db::Job> {
+        let user_id = executor.context().writer()?.id;
 
         match (service.as_str(), num_days < 0) {
             ("fitbit", false) => Ok(()),
-            ("fitbit", true) => Err("num_days must be positive".to_owned()),
-            _ => Err("only fitbit is supported".to_owned())
-        }?;
+            ("fitbit", true) => Err(AppError::BadRequest("num_days must be positive".to_owned())),
+            _ => Err(AppError::BadRequest("only fitbit is supported".to_owned()))
+        }.field_err()?;
 
-        let action = QueueAction {
-            id: Uuid::new_v4(),
-            user_id: user_id,
-            params: QueueActionParams::BulkIngestIntraday(
+        let action = QueueAction::new(
+            user_id,
+            QueueActionParams::BulkIngestIntraday(
                 measurement,
                 // TODO naive_local or naive_utc?
                 date.unwrap_or_else(|| Utc::now().naive_local().date()),
                 num_days as u32
             )
+        );
+
+        enqueue(executor.context(), action)
+    }
+
+    field sync_since(&executor, service: String, measurement: IntradayMetric, fill_holes = true: bool) -> FieldResult<db::Job> {
+        let user_id = executor.context().writer()?.id;
+
+        match service.as_str() {
+            "fitbit" => Ok(()),
+            _ => Err(AppError::BadRequest("only fitbit is supported".to_owned()))
+        }.field_err()?;
+
+        let action = QueueAction::new(
+            user_id,
+            QueueActionParams::SyncSince(measurement, fill_holes)
+        );
+
+        enqueue(executor.context(), action)
+    }
+
+    field ingest_sleep(&executor, service: String, date: Option<NaiveDate>, num_days = 1: i32) -> FieldResult<db::Job> {
+        let user_id = executor.context().writer()?.id;
+
+        match (service.as_str(), num_days < 0) {
+            ("fitbit", false) => Ok(()),
+            ("fitbit", true) => Err(AppError::BadRequest("num_days must be positive".to_owned())),
+            _ => Err(AppError::BadRequest("only fitbit is supported".to_owned()))
+        }.field_err()?;
+
+        let action = QueueAction::new(
+            user_id,
+            QueueActionParams::BulkIngestSleep(
+                date.unwrap_or_else(|| Utc::now().naive_local().date()),
+                num_days as u32
+            )
+        );
+
+        enqueue(executor.context(), action)
+    }
+
+    field ingest_body(&executor, service: String, start_date: Option<NaiveDate>, end_date: Option<NaiveDate>) -> FieldResult<db::Job> {
+        let user_id = executor.context().writer()?.id;
+
+        let today = Utc::now().naive_local().date();
+        let start_date = start_date.unwrap_or(today);
+        let end_date = end_date.unwrap_or(today);
+
+        match (service.as_str(), start_date > end_date) {
+            ("fitbit", false) => Ok(()),
+            ("fitbit", true) => Err(AppError::BadRequest("start_date must not be after end_date".to_owned())),
+            _ => Err(AppError::BadRequest("only fitbit is supported".to_owned()))
+        }.field_err()?;
+
+        let action = QueueAction::new(
+            user_id,
+            QueueActionParams::IngestBody(start_date, end_date)
+        );
+
+        enqueue(executor.context(), action)
+    }
+
+    field update_sync_settings(&executor, service: String, interval_minutes: Option<i32>, metrics: Option<Vec<IntradayMetric>>, enabled: Option<bool>) -> FieldResult<db::SyncSetting> {
+        let conn = &executor.context().conn;
+        let user_id = executor.context().writer()?.id;
+
+        match service.as_str() {
+            "fitbit" => Ok(()),
+            _ => Err(AppError::BadRequest("only fitbit is supported".to_owned()))
+        }.field_err()?;
+
+        if interval_minutes.map_or(false, |i| i < db::MIN_SYNC_INTERVAL_MINUTES) {
+            Err(AppError::BadRequest(format!("interval_minutes must be at least {}", db::MIN_SYNC_INTERVAL_MINUTES))
+                .into_field_error())?;
+        }
+
+        let update = db::UpdateSyncSetting {
+            interval_minutes,
+            metrics: metrics.map(|metrics| metrics.iter().map(|m| m.as_str().to_string()).collect()),
+            enabled
         };
+        let setting = db::SyncSetting::update(conn, &user_id, &service, &update).field_err()?;
+
+        Ok(setting)
+    }
+
+    field disconnect_service(&executor, service: String, delete_data = false: bool) -> FieldResult<bool> {
+        let conn = &executor.context().conn;
+        let keyring = &executor.context().keyring;
+        let user_id = executor.context().writer()?.id;
+
+        let token = db::Token::find_by_uid_service(conn, keyring, &user_id, &service)
+            .map_err(|_| AppError::NotFound(format!("{} is not connected", service)))
+            .field_err()?;
+
+        conn.transaction::<_, diesel::result::Error, _>(|| {
+            db::Job::cancel_pending(conn, &user_id, &service)?;
+            db::SyncCursor::delete_for_service(conn, &user_id, &service)?;
+            db::Token::delete(conn, token.id)?;
+            if delete_data {
+                db::delete_from_source(conn, &user_id, &service)?;
+            }
+            Ok(())
+        }).field_err()?;
+
+        // only once the token is gone here, a failed revoke only means it lives on at the provider
+        if let Err(e) = executor.context().oauth.revoke(&token) {
+            warn!("Couldn't revoke {} token of {}: {}", service, user_id, e);
+        }
+
+        if delete_data {
+            for metric in IntradayMetric::all() {
+                if let Err(e) = db::refresh_all_rollups(conn, metric.table()) {
+                    warn!("Couldn't refresh {} rollups: {}", metric.table(), e);
+                }
+            }
+        }
+
+        Ok(true)
+    }
+
+    field grant_access(&executor, grantee_email: String, metrics: Vec<GrantMetric>, start_time: Option<DateTime<Utc>>, end_time: Option<DateTime<Utc>>, expires_at: Option<DateTime<Utc>>) -> FieldResult<db::Grant> {
+        let conn = &executor.context().conn;
+        let user_id = executor.context().writer()?.id;
+
+        let grantee = db::User::find_one_by_email(conn, &grantee_email)
+            .map_err(|_| AppError::NotFound(format!("no user with email {}", grantee_email)))
+            .field_err()?;
+        if grantee.id == user_id {
+            Err(AppError::BadRequest("can't grant access to yourself".to_owned())
+                .into_field_error())?;
+        }
+        if metrics.is_empty() {
+            Err(AppError::BadRequest("metrics must not be empty".to_owned()).into_field_error())?;
+        }
+        if let (Some(start_time), Some(end_time)) = (start_time, end_time) {
+            if start_time >= end_time {
+                Err(AppError::BadRequest("start_time must be before end_time".to_owned())
+                    .into_field_error())?;
+            }
+        }
+
+        let grant = db::Grant::insert(conn, &db::Grant {
+            id: Uuid::new_v4(),
+            owner_id: user_id,
+            grantee_id: grantee.id,
+            metrics: metrics.iter().map(|m| m.as_str().to_string()).collect(),
+            start_time,
+            end_time,
+            expires_at,
+            created_at: Utc::now()
+        }).field_err()?;
+
+        Ok(grant)
+    }
+
+    // the grantee can give a grant back too
+    field revoke_access(&executor, id: Uuid) -> FieldResult<bool> {
+        let conn = &executor.context().conn;
+        let user_id = executor.context().writer()?.id;
+
+        let grant = db::Grant::find_one(conn, &id)
+            .ok()
+            .filter(|grant| grant.owner_id == user_id || grant.grantee_id == user_id)
+            .ok_or_else(|| AppError::NotFound("no such grant".to_owned()))
+            .field_err()?;
+        db::Grant::delete(conn, &grant.id).field_err()?;
+
+        Ok(true)
+    }
+
+    field unlink_identity(&executor, provider: String, subject: String) -> FieldResult<bool> {
+        let conn = &executor.context().conn;
+        let user_id = executor.context().writer()?.id;
+
+        if db::Identity::for_user(conn, &user_id).field_err()?.len() <= 1 {
+            Err(AppError::BadRequest("can't unlink the only account you can log in with".to_owned())
+                .into_field_error())?;
+        }
+        if db::Identity::delete(conn, &user_id, (&provider, &subject)).field_err()? == 0 {
+            Err(AppError::NotFound("no such identity".to_owned()).into_field_error())?;
+        }
+
+        Ok(true)
+    }
+
+    field create_access_token(&executor, name: String, scope = (db::AccessTokenScope::Read): db::AccessTokenScope, expires_at: Option<DateTime<Utc>>) -> FieldResult<CreatedAccessToken> {
+        let conn = &executor.context().conn;
+        let user_id = executor.context().writer()?.id;
+
+        // a leaked token shouldn't be able to mint more
+        if executor.context().scope.is_some() {
+            Err(AppError::Forbidden("access tokens can only be created when logged in".to_owned())
+                .into_field_error())?;
+        }
+        if expires_at.map_or(false, |expires_at| expires_at <= Utc::now()) {
+            Err(AppError::BadRequest("expires_at must be in the future".to_owned())
+                .into_field_error())?;
+        }
+
+        let (access_token, token) = db::AccessToken::create(conn, &user_id, &name, scope, expires_at).field_err()?;
+
+        Ok(CreatedAccessToken {
+            access_token,
+            token
+        })
+    }
+
+    field revoke_access_token(&executor, id: Uuid) -> FieldResult<bool> {
+        let conn = &executor.context().conn;
+        let user_id = executor.context().writer()?.id;
 
-        producer.push(action)?;
+        if db::AccessToken::delete(conn, &user_id, &id).field_err()? == 0 {
+            Err(AppError::NotFound("no such access token".to_owned()).into_field_error())?;
+        }
 
         Ok(true)
     }
 
     field add_mood(&executor, mood: i32, note: String) -> FieldResult<bool> {
-        let user_id = executor.context().user.clone().ok_or_else(|| "Not logged in".to_owned())?.id;
+        let user_id = executor.context().writer()?.id;
         let conn = &executor.context().conn;
 
         if mood <= 0 || mood > 10 {
-            Err("mood must be a number between 1 and 10".to_owned())
-        } else { Ok(()) }?;
+            Err(AppError::BadRequest("mood must be a number between 1 and 10".to_owned())
+                .into_field_error())?;
+        }
 
         db::Mood::insert(conn, &db::Mood {
             time: Utc::now(),
             mood: mood,
             note: note,
             user_id: user_id
-        })?;
+        }).field_err()?;
 
         Ok(true)
     }
@@ -0,0 +1,32 @@
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate diesel;
+#[macro_use]
+extern crate juniper;
+#[macro_use]
+extern crate log;
+
+pub mod config;
+pub mod crypto;
+pub mod db;
+pub mod error;
+pub mod graphql;
+mod middlewares;
+pub mod oauth;
+pub mod providers;
+pub mod queue;
+pub mod rate_limit;
+pub mod scheduler;
+pub mod utils;
+pub mod webhooks;
+pub mod worker;
+
+use actix::prelude::*;
+
+pub struct AppState {
+    pub db: Addr<db::DbExecutor>,
+    pub graphql: Addr<graphql::GraphQLExecutor>,
+    pub oauth: Addr<oauth::OAuthExecutor>,
+    pub webhooks: Addr<webhooks::WebhookExecutor>,
+}
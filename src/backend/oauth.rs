@@ -1,21 +1,26 @@
 use actix::prelude::{Actor, Addr, Handler, Message, SyncContext};
 use actix_web::middleware::identity::RequestIdentity;
+use actix_web::middleware::session::RequestSession;
 use actix_web::{
-    error, http::header, AsyncResponder, FromRequest, FutureResponse, HttpRequest, HttpResponse,
-    Path, Query,
+    http::header, AsyncResponder, FromRequest, FutureResponse, HttpRequest, HttpResponse, Path,
+    Query,
 };
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Duration, Utc};
 use diesel::pg::PgConnection;
 use futures::{
-    future::{err, ok, result},
+    future::{err, ok},
     Future,
 };
+use reqwest::StatusCode;
+use sha2::{Digest, Sha256};
 use std::collections::HashMap;
 use std::fmt::{self, Display};
 use uuid::Uuid;
 
 use super::AppState;
-use crate::db::{self, DbExecutor, UpsertToken, UpsertUser};
+use crate::crypto::Keyring;
+use crate::db::{self, DbExecutor, Login, UpsertToken};
+use crate::error::AppError;
 
 #[derive(Serialize, Deserialize)]
 pub struct OAuthToken {
@@ -26,7 +31,10 @@ pub struct OAuthToken {
     pub scopes: Vec<String>,
     pub user_id: String,
     pub email: Option<String>,
-    pub g_sub: Option<String>,
+    /// The subject to log in with, from providers that identify users
+    pub identity: Option<String>,
+    pub timezone: Option<String>,
+    pub utc_offset_ms: Option<i32>,
 }
 
 impl From<db::Token> for OAuthToken {
@@ -36,11 +44,70 @@ impl From<db::Token> for OAuthToken {
             access_token: t.access_token,
             expiration: t.access_token_expiry,
             refresh_token: t.refresh_token,
-            // todo
-            scopes: vec![],
+            scopes: t.scopes,
             user_id: t.service_userid,
             email: None,
-            g_sub: None,
+            identity: None,
+            timezone: t.timezone,
+            utc_offset_ms: t.utc_offset_ms,
+        }
+    }
+}
+
+/// How long someone has to finish authorizing before the state in their session is stale.
+pub static OAUTH_STATE_TTL_MINUTES: i64 = 10;
+
+static OAUTH_STATE_SESSION_KEY: &'static str = "oauth_state";
+
+/// What we remember about an authorization request across the redirect, in the session. The
+/// `state` is sent along and has to come back unchanged, which ties the callback to the browser
+/// that started it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OAuthState {
+    pub service: String,
+    pub state: String,
+    /// PKCE code verifier, for providers that support it
+    pub code_verifier: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl OAuthState {
+    pub fn new(service: &str, pkce: bool) -> OAuthState {
+        OAuthState {
+            service: service.to_owned(),
+            state: Uuid::new_v4().to_simple().to_string(),
+            // 64 random hex characters, within the 43-128 PKCE allows
+            code_verifier: if pkce {
+                Some(format!(
+                    "{}{}",
+                    Uuid::new_v4().to_simple(),
+                    Uuid::new_v4().to_simple()
+                ))
+            } else {
+                None
+            },
+            created_at: Utc::now(),
+        }
+    }
+
+    /// The S256 PKCE challenge for our verifier.
+    pub fn code_challenge(&self) -> Option<String> {
+        self.code_verifier.as_ref().map(|verifier| {
+            base64::encode_config(
+                &Sha256::digest(verifier.as_bytes()),
+                base64::URL_SAFE_NO_PAD,
+            )
+        })
+    }
+
+    /// Checks a callback against the state we sent.
+    pub fn validate(&self, service: &str, state: &str) -> Result<(), OAuthError> {
+        if self.service != service || self.state != state {
+            Err(OAuthError::Error("OAuth state mismatch".to_owned()))
+        } else if self.created_at + Duration::minutes(OAUTH_STATE_TTL_MINUTES) < Utc::now() {
+            Err(OAuthError::Error("OAuth state expired".to_owned()))
+        } else {
+            Ok(())
         }
     }
 }
@@ -51,6 +118,10 @@ pub enum OAuthError {
     Reqwest(reqwest::Error),
     ActixError(actix_web::error::Error),
     TokenError(String),
+    /// An id_token that didn't verify, and why
+    InvalidIdToken(String),
+    /// The provider won't refresh the token anymore, the user has to connect again
+    GrantRejected,
     Error(String),
 }
 
@@ -60,12 +131,36 @@ impl Display for OAuthError {
             OAuthError::DotEnv(e) => write!(f, "{}", e),
             OAuthError::Reqwest(e) => write!(f, "{}", e),
             OAuthError::TokenError(e) => write!(f, "{}", e),
+            OAuthError::InvalidIdToken(e) => write!(f, "invalid id_token: {}", e),
+            OAuthError::GrantRejected => write!(
+                f,
+                "access was revoked or has expired, connect the service again"
+            ),
             OAuthError::Error(e) => write!(f, "{}", e),
             OAuthError::ActixError(e) => write!(f, "{}", e),
         }
     }
 }
 
+impl std::error::Error for OAuthError {}
+
+/// Checks the response to a refresh. A 401, or a 400 with `invalid_grant`, means the refresh token
+/// was revoked or expired, which is `GrantRejected`. Anything else is left for the caller to retry.
+pub fn check_refresh_response(
+    mut response: reqwest::Response,
+) -> Result<reqwest::Response, OAuthError> {
+    match response.status() {
+        StatusCode::UNAUTHORIZED => Err(OAuthError::GrantRejected),
+        StatusCode::BAD_REQUEST if response.text()?.contains("invalid_grant") => {
+            Err(OAuthError::GrantRejected)
+        }
+        StatusCode::BAD_REQUEST => {
+            Err(OAuthError::TokenError("refresh failed with 400".to_owned()))
+        }
+        _ => Ok(response.error_for_status()?),
+    }
+}
+
 impl From<dotenv::Error> for OAuthError {
     fn from(e: dotenv::Error) -> Self {
         OAuthError::DotEnv(e)
@@ -85,24 +180,51 @@ impl From<actix_web::error::Error> for OAuthError {
 }
 
 pub trait OAuthProvider {
-    fn oauth_redirect_url(&self) -> Result<String, OAuthError>;
-    fn token_from_code(&self, code: &str) -> Result<OAuthToken, OAuthError>;
+    fn oauth_redirect_url(&self, state: &OAuthState) -> Result<String, OAuthError>;
+    fn token_from_code(&self, code: &str, state: &OAuthState) -> Result<OAuthToken, OAuthError>;
     fn refresh_token(&self, token: OAuthToken) -> Result<OAuthToken, OAuthError>;
+    /// Revokes our access upstream, so the tokens stop working even if they leak.
+    fn revoke_token(&self, token: &db::Token) -> Result<(), OAuthError>;
     fn name(&self) -> &'static str;
 
+    fn supports_pkce(&self) -> bool {
+        false
+    }
+
     fn refresh_and_update(
         &self,
         conn: &PgConnection,
+        keyring: &Keyring,
         user_id: &Uuid,
     ) -> Result<db::Token, OAuthError> {
-        let token = db::Token::find_by_uid_service(conn, user_id, self.name())
+        let token = db::Token::find_by_uid_service(conn, keyring, user_id, self.name())
             .map_err(|_e| OAuthError::Error("couldn't find token".to_owned()))?;
         let id = token.id;
 
         if token.access_token_expiry < Utc::now() {
-            let refreshed_token = self.refresh_token(OAuthToken::from(token))?;
+            let refreshed_token = match self.refresh_token(OAuthToken::from(token)) {
+                Ok(refreshed_token) => refreshed_token,
+                Err(OAuthError::GrantRejected) => {
+                    // shown to the user, who has to connect again
+                    let e = OAuthError::GrantRejected;
+                    if let Err(db_e) = db::Token::record_refresh_error(conn, id, &e.to_string()) {
+                        warn!("Couldn't record refresh error of token {}: {}", id, db_e);
+                    }
+                    return Err(e);
+                }
+                Err(e) => {
+                    // likely to pass, like timeouts and the provider being down. The details are
+                    // only for our logs, the error ends up on the user's job.
+                    warn!("Couldn't refresh token {}: {}", id, e);
+                    return Err(OAuthError::Error(format!(
+                        "couldn't refresh the {} token",
+                        self.name()
+                    )));
+                }
+            };
             db::Token::update(
                 conn,
+                keyring,
                 id,
                 &db::UpdateToken {
                     access_token: Some(&refreshed_token.access_token),
@@ -112,6 +234,9 @@ pub trait OAuthProvider {
                         "" => None,
                         t => Some(&t),
                     },
+                    timezone: refreshed_token.timezone.as_ref().map(String::as_str),
+                    utc_offset_ms: refreshed_token.utc_offset_ms,
+                    scopes: Some(&refreshed_token.scopes),
                 },
             )
             .map_err(|_e| OAuthError::Error("couldn't update token".to_owned()))
@@ -132,20 +257,27 @@ impl OAuth {
         }
     }
 
-    pub fn redirect_url(&self, service: &str) -> Result<String, OAuthError> {
+    /// Where to send the user to authorize us, along with the state to keep until they're back.
+    pub fn redirect_url(&self, service: &str) -> Result<(String, OAuthState), OAuthError> {
         let provider = self
             .providers
             .get(&service.to_string())
             .ok_or_else(|| OAuthError::Error("Service not implemented".to_string()))?;
-        provider.oauth_redirect_url()
+        let state = OAuthState::new(service, provider.supports_pkce());
+        Ok((provider.oauth_redirect_url(&state)?, state))
     }
 
-    pub fn callback(&self, service: &str, code: &str) -> Result<OAuthToken, OAuthError> {
+    pub fn callback(
+        &self,
+        service: &str,
+        code: &str,
+        state: &OAuthState,
+    ) -> Result<OAuthToken, OAuthError> {
         let provider = self
             .providers
             .get(&service.to_string())
             .ok_or_else(|| OAuthError::Error("Service not implemented".to_string()))?;
-        provider.token_from_code(code)
+        provider.token_from_code(code, state)
     }
 
     pub fn refresh_token(&self, token: OAuthToken) -> Result<OAuthToken, OAuthError> {
@@ -156,17 +288,26 @@ impl OAuth {
         provider.refresh_token(token)
     }
 
+    pub fn revoke(&self, token: &db::Token) -> Result<(), OAuthError> {
+        let provider = self
+            .providers
+            .get(&token.service)
+            .ok_or_else(|| OAuthError::Error("Service not implemented".to_string()))?;
+        provider.revoke_token(token)
+    }
+
     pub fn refresh_and_update(
         &self,
         service: &str,
         conn: &PgConnection,
+        keyring: &Keyring,
         user_id: &Uuid,
     ) -> Result<db::Token, OAuthError> {
         let provider = self
             .providers
             .get(service)
             .ok_or_else(|| OAuthError::Error("Service not implemented".to_string()))?;
-        provider.refresh_and_update(conn, user_id)
+        provider.refresh_and_update(conn, keyring, user_id)
     }
 }
 
@@ -180,11 +321,11 @@ impl Actor for OAuthExecutor {
 pub struct OAuthRequest(String);
 
 impl Message for OAuthRequest {
-    type Result = Result<String, OAuthError>;
+    type Result = Result<(String, OAuthState), OAuthError>;
 }
 
 impl Handler<OAuthRequest> for OAuthExecutor {
-    type Result = Result<String, OAuthError>;
+    type Result = Result<(String, OAuthState), OAuthError>;
     fn handle(&mut self, msg: OAuthRequest, _: &mut Self::Context) -> Self::Result {
         let oauth = &self.0;
         oauth.redirect_url(msg.0.as_str())
@@ -195,6 +336,7 @@ impl Handler<OAuthRequest> for OAuthExecutor {
 pub struct OAuthCallback {
     pub service: String,
     pub code: String,
+    pub state: OAuthState,
 }
 
 impl Message for OAuthCallback {
@@ -205,7 +347,7 @@ impl Handler<OAuthCallback> for OAuthExecutor {
     type Result = Result<OAuthToken, OAuthError>;
     fn handle(&mut self, msg: OAuthCallback, _: &mut Self::Context) -> Self::Result {
         let oauth = &self.0;
-        oauth.callback(msg.service.as_str(), msg.code.as_str())
+        oauth.callback(msg.service.as_str(), msg.code.as_str(), &msg.state)
     }
 }
 
@@ -228,51 +370,62 @@ pub fn start_oauth(req: &HttpRequest<AppState>) -> FutureResponse<HttpResponse>
     let service =
         Path::<String>::extract(&req).unwrap_or_else(|_| Path::<String>::from("not-a-service".to_owned()));
     let oauth = &req.state().oauth;
+    let req = req.clone();
 
     oauth
         .send(OAuthRequest(service.to_string()))
         .from_err()
-        .and_then(|res| match res {
-            Ok(url) => Ok(HttpResponse::Found().header(header::LOCATION, url).finish()),
+        .and_then(move |res| match res {
+            Ok((url, state)) => {
+                req.session().set(OAUTH_STATE_SESSION_KEY, state)?;
+                Ok(HttpResponse::Found().header(header::LOCATION, url).finish())
+            }
             Err(e) => {
                 error!("{}", e);
-                Err(error::ErrorBadRequest("Bad request"))
+                Err(AppError::BadRequest("Bad request".to_owned()).into())
             }
         })
         .responder()
 }
 
+/// Tokens from providers that identify users log in (or link the identity to whoever is logged
+/// in already), the rest can only be connected by someone who is logged in.
 fn try_login(
     db: &Addr<DbExecutor>,
     maybe_userid: &Option<String>,
     token: &OAuthToken,
-) -> FutureResponse<String> {
-    if let Some(id) = maybe_userid {
-        info!("Already logged in as: {}", id);
-        return Box::new(result::<String, actix_web::Error>(Ok(id.to_string())));
-    } else if let Some(g_sub) = token.g_sub.clone() {
-        db.send(UpsertUser {
-            email: match &token.email {
-                Some(email) => email,
-                None => unimplemented!(),
-            }
-            .clone(),
-            g_sub,
-        })
-        .from_err()
-        .and_then(|res| match res {
-            Ok(user) => {
-                info!("Logged in as {} ({})", user.id, user.email);
-                Ok(user.id.to_string())
-            }
-            Err(e) => {
-                error!("{}", e);
-                unimplemented!()
-            },
-        })
-        .responder()
-    } else {
-        unimplemented!()
+) -> FutureResponse<Uuid> {
+    let user_id = maybe_userid
+        .as_ref()
+        .and_then(|id| Uuid::parse_str(id).ok());
+
+    match (&token.identity, user_id) {
+        (Some(subject), user_id) => db
+            .send(Login {
+                provider: token.service.clone(),
+                subject: subject.clone(),
+                email: token.email.clone(),
+                user_id,
+            })
+            .from_err()
+            .and_then(|res| match res {
+                Ok(user) => {
+                    info!("Logged in as {} ({})", user.id, user.email);
+                    Ok(user.id)
+                }
+                Err(e) => {
+                    warn!("Login failed: {}", e);
+                    Err(e.into())
+                }
+            })
+            .responder(),
+        (None, Some(id)) => {
+            info!("Already logged in as: {}", id);
+            Box::new(ok::<_, actix_web::Error>(id))
+        }
+        (None, None) => Box::new(err::<_, actix_web::Error>(
+            AppError::Unauthenticated(format!("Log in before connecting {}", token.service)).into(),
+        )),
     }
 }
 
@@ -285,13 +438,30 @@ pub fn oauth_callback(
 
     let service = Path::<String>::extract(&req)?.into_inner();
     let query = Query::<HashMap<String, String>>::extract(&req)?;
+    if let Some(e) = query.get("error") {
+        warn!("{} authorization failed: {}", service, e);
+        return Err(AppError::BadRequest("Bad request".to_owned()).into());
+    }
     let code = query
         .get("code")
-        .ok_or_else(|| error::ErrorBadRequest("Bad request"))?
+        .ok_or_else(|| AppError::BadRequest("Bad request".to_owned()))?
         .to_string();
+
+    // the state is single use, whether or not it checks out
+    let state = req.session().get::<OAuthState>(OAUTH_STATE_SESSION_KEY)?;
+    req.session().remove(OAUTH_STATE_SESSION_KEY);
+    let state = state.ok_or_else(|| AppError::BadRequest("Bad request".to_owned()))?;
+    state
+        .validate(&service, query.get("state").map_or("", String::as_str))
+        .map_err(|e| {
+            warn!("Rejecting {} callback: {}", service, e);
+            AppError::BadRequest("Bad request".to_owned())
+        })?;
+
     let params = OAuthCallback {
         service,
         code,
+        state,
     };
 
     Ok(oauth
@@ -299,25 +469,28 @@ pub fn oauth_callback(
         .from_err()
         .and_then(|maybe_token| match maybe_token {
             Ok(token) => ok(token),
-            Err(e) => err(error::ErrorInternalServerError(e)),
+            Err(e) => err(AppError::from(e).into()),
         })
         .and_then(move |t| {
             try_login(&db, &req.identity(), &t)
                 .and_then(move |user_id| {
-                    req.remember(user_id.clone());
+                    req.remember(user_id.to_string());
                     db.send(UpsertToken {
                         access_token: t.access_token,
                         access_token_expiry: t.expiration,
                         refresh_token: t.refresh_token,
                         service: t.service,
                         service_userid: t.user_id,
-                        user_id: Uuid::parse_str(&user_id).unwrap(),
+                        user_id,
+                        timezone: t.timezone,
+                        utc_offset_ms: t.utc_offset_ms,
+                        scopes: t.scopes,
                     })
                     .from_err()
                 })
                 .and_then(|res| match res {
                     Ok(_) => Ok(HttpResponse::Found().header(header::LOCATION, "/").finish()),
-                    Err(e) => Ok(HttpResponse::InternalServerError().body(e.to_string())),
+                    Err(e) => Err(e.into()),
                 })
                 .responder()
         })
@@ -0,0 +1,114 @@
+use super::{get_json, local_to_utc, local_tz};
+use crate::db::{BodyMeasurement, BodyMeasurementKind, Token};
+use crate::rate_limit::RateLimiter;
+use actix_web::{error, Error};
+use chrono::{DateTime, Duration, NaiveDate, NaiveDateTime, Utc};
+use chrono_tz::Tz;
+use serde::de::DeserializeOwned;
+
+/// Fitbit rejects body log ranges longer than this.
+pub static FITBIT_BODY_MAX_DAYS: i64 = 31;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WeightLog {
+    pub date: String,
+    pub time: String,
+    // metric units, since we don't send an Accept-Language header
+    pub weight: f64,
+    pub bmi: Option<f64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FatLog {
+    pub date: String,
+    pub time: String,
+    pub fat: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WeightResponse {
+    pub weight: Vec<WeightLog>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FatResponse {
+    pub fat: Vec<FatLog>,
+}
+
+fn time_utc(date: &str, time: &str, local_tz: Tz) -> Result<DateTime<Utc>, Error> {
+    let naive_dt =
+        NaiveDateTime::parse_from_str(&format!("{}T{}", date, time), "%Y-%m-%dT%H:%M:%S")
+            .map_err(error::ErrorInternalServerError)?;
+
+    local_to_utc(&naive_dt, local_tz)
+        .ok_or_else(|| error::ErrorInternalServerError("error converting timestamp"))
+}
+
+fn get_body_log<T: DeserializeOwned>(
+    log: &str,
+    start: NaiveDate,
+    end: NaiveDate,
+    token: &Token,
+    limiter: &RateLimiter,
+) -> Result<T, Error> {
+    let endpoint = format!(
+        "https://api.fitbit.com/1/user/-/body/log/{}/date/{}/{}.json",
+        log,
+        start.format("%Y-%m-%d"),
+        end.format("%Y-%m-%d")
+    );
+
+    get_json(&endpoint, token, limiter)
+}
+
+/// Weight, BMI and body fat logged between `start` and `end` (inclusive). Ranges longer than
+/// Fitbit's limit are fetched in several requests.
+pub fn body_measurements_for_range(
+    start: NaiveDate,
+    end: NaiveDate,
+    token: &Token,
+    limiter: &RateLimiter,
+) -> Result<Vec<BodyMeasurement>, Error> {
+    let tz = local_tz(token)?;
+    let mut measurements = vec![];
+
+    let mut window_start = start;
+    while window_start <= end {
+        let window_end =
+            std::cmp::min(window_start + Duration::days(FITBIT_BODY_MAX_DAYS - 1), end);
+
+        let weights: WeightResponse =
+            get_body_log("weight", window_start, window_end, token, limiter)?;
+        for log in weights.weight {
+            let time = time_utc(&log.date, &log.time, tz)?;
+            measurements.push(BodyMeasurement::new(
+                token.user_id,
+                time,
+                BodyMeasurementKind::Weight,
+                log.weight,
+            ));
+            if let Some(bmi) = log.bmi {
+                measurements.push(BodyMeasurement::new(
+                    token.user_id,
+                    time,
+                    BodyMeasurementKind::Bmi,
+                    bmi,
+                ));
+            }
+        }
+
+        let fats: FatResponse = get_body_log("fat", window_start, window_end, token, limiter)?;
+        for log in fats.fat {
+            measurements.push(BodyMeasurement::new(
+                token.user_id,
+                time_utc(&log.date, &log.time, tz)?,
+                BodyMeasurementKind::Fat,
+                log.fat,
+            ));
+        }
+
+        window_start = window_end + Duration::days(1);
+    }
+
+    Ok(measurements)
+}
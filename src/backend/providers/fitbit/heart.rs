@@ -0,0 +1,76 @@
+use super::{local_to_utc, local_tz, IntradayResponse};
+use crate::db::{HeartRateZone, RestingHeartRate, Token};
+use actix_web::{error, Error};
+use chrono::{DateTime, NaiveDate, Utc};
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HeartRateZoneSummary {
+    pub name: String,
+    pub min: i32,
+    pub max: i32,
+    // both are missing on days without any data
+    pub minutes: Option<i32>,
+    pub calories_out: Option<f64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HeartRateDayValue {
+    pub heart_rate_zones: Vec<HeartRateZoneSummary>,
+    pub resting_heart_rate: Option<i32>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HeartRateDay {
+    pub date_time: String,
+    pub value: HeartRateDayValue,
+}
+
+fn day_start_utc(day: NaiveDate, token: &Token) -> Result<DateTime<Utc>, Error> {
+    local_to_utc(&day.and_hms(0, 0, 0), local_tz(token)?)
+        .ok_or_else(|| error::ErrorInternalServerError("error converting timestamp"))
+}
+
+/// The daily resting heart rate and zone breakdown that come along with an intraday heart rate
+/// response. Both are stamped at local midnight of `day`.
+pub fn heart_rate_summary(
+    day: NaiveDate,
+    token: &Token,
+    resp: &IntradayResponse,
+) -> Result<(Option<RestingHeartRate>, Vec<HeartRateZone>), Error> {
+    let summary = match resp.activities_heart.as_ref().and_then(|days| days.first()) {
+        Some(summary) => summary,
+        None => return Ok((None, vec![])),
+    };
+    let time = day_start_utc(day, token)?;
+
+    let resting = summary
+        .value
+        .resting_heart_rate
+        .map(|count| RestingHeartRate {
+            time,
+            user_id: token.user_id,
+            source: "fitbit".to_string(),
+            count,
+        });
+
+    let zones = summary
+        .value
+        .heart_rate_zones
+        .iter()
+        .map(|zone| HeartRateZone {
+            time,
+            user_id: token.user_id,
+            source: "fitbit".to_string(),
+            name: zone.name.clone(),
+            min: zone.min,
+            max: zone.max,
+            minutes: zone.minutes.unwrap_or(0),
+            calories_out: zone.calories_out.unwrap_or(0.0),
+        })
+        .collect();
+
+    Ok((resting, zones))
+}
@@ -1,19 +1,79 @@
-use super::local_tz;
-use crate::db::Token;
+use super::{get_json, local_tz, HeartRateDay, LocalClock};
+use crate::db::{self, Measurement, Token};
+use crate::rate_limit::RateLimiter;
 use actix_web::{error, Error};
-use chrono::{offset::TimeZone, DateTime, NaiveDate, NaiveDateTime, Utc};
-use chrono_tz::Tz;
-use reqwest;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use std::collections::BTreeMap;
 use std::fmt::Debug;
-use uuid::Uuid;
+use std::str::FromStr;
 
-#[derive(GraphQLEnum, Serialize, Deserialize, Debug, Clone)]
+#[derive(GraphQLEnum, Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum IntradayMetric {
     Step,
     Calorie,
     Distance,
     Elevation,
     Floor,
+    HeartRate,
+}
+
+impl IntradayMetric {
+    pub fn all() -> Vec<IntradayMetric> {
+        vec![
+            IntradayMetric::Step,
+            IntradayMetric::Calorie,
+            IntradayMetric::Distance,
+            IntradayMetric::Elevation,
+            IntradayMetric::Floor,
+            IntradayMetric::HeartRate,
+        ]
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IntradayMetric::Step => "step",
+            IntradayMetric::Calorie => "calorie",
+            IntradayMetric::Distance => "distance",
+            IntradayMetric::Elevation => "elevation",
+            IntradayMetric::Floor => "floor",
+            IntradayMetric::HeartRate => "heart_rate",
+        }
+    }
+
+    /// The hypertable the metric is stored in.
+    pub fn table(&self) -> &'static str {
+        match self {
+            IntradayMetric::Step => db::Step::TABLE,
+            IntradayMetric::Calorie => db::Calorie::TABLE,
+            IntradayMetric::Distance => db::Distance::TABLE,
+            IntradayMetric::Elevation => db::Elevation::TABLE,
+            IntradayMetric::Floor => db::Floor::TABLE,
+            IntradayMetric::HeartRate => db::HeartRate::TABLE,
+        }
+    }
+
+    /// What a grant has to include to share the metric.
+    pub fn grant_metric(&self) -> db::GrantMetric {
+        match self {
+            IntradayMetric::Step => db::GrantMetric::Step,
+            IntradayMetric::Calorie => db::GrantMetric::Calorie,
+            IntradayMetric::Distance => db::GrantMetric::Distance,
+            IntradayMetric::Elevation => db::GrantMetric::Elevation,
+            IntradayMetric::Floor => db::GrantMetric::Floor,
+            IntradayMetric::HeartRate => db::GrantMetric::HeartRate,
+        }
+    }
+}
+
+impl FromStr for IntradayMetric {
+    type Err = String;
+
+    fn from_str(metric: &str) -> Result<IntradayMetric, String> {
+        IntradayMetric::all()
+            .into_iter()
+            .find(|m| m.as_str() == metric)
+            .ok_or_else(|| format!("unknown metric {}", metric))
+    }
 }
 
 pub trait IntradayMeasurement: Sized + Debug {
@@ -24,6 +84,12 @@ pub trait IntradayMeasurement: Sized + Debug {
     ) -> Result<Self, Error>;
     fn parse_response(r: IntradayResponse) -> Option<Vec<IntradayValue>>;
     fn name() -> &'static str;
+
+    /// Resolution to request, "1min" or "1sec" (which only heart rate offers). Parsing doesn't
+    /// care which one comes back.
+    fn detail_level() -> &'static str {
+        "1min"
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -62,17 +128,16 @@ impl IntradayValue {
         }
     }
 
-    fn time_utc(&self, day: NaiveDate, local_tz: Tz) -> Result<DateTime<Utc>, Error> {
+    fn time_utc(&self, day: NaiveDate, clock: &mut LocalClock) -> Result<DateTime<Utc>, Error> {
         let naive_dt = NaiveDateTime::parse_from_str(
             &format!("{}T{}", day.format("%m-%d-%Y"), self.time_str()),
             "%m-%d-%YT%H:%M:%S",
         )
         .map_err(error::ErrorInternalServerError)?;
-        let local_dt = local_tz.from_local_datetime(&naive_dt).earliest().ok_or_else(||
-            error::ErrorInternalServerError("error converting timestamp"),
-        )?;
 
-        Ok(Utc.from_utc_datetime(&local_dt.naive_utc()))
+        clock
+            .to_utc(&naive_dt)
+            .ok_or_else(|| error::ErrorInternalServerError("error converting timestamp"))
     }
 }
 
@@ -89,45 +154,56 @@ pub struct IntradayResponse {
     pub activities_distance_intraday: Option<IntradayDataset<IntradayFloat>>,
     pub activities_floors_intraday: Option<IntradayDataset<IntradayIntegral>>,
     pub activities_elevation_intraday: Option<IntradayDataset<IntradayFloat>>,
+    pub activities_heart: Option<Vec<HeartRateDay>>,
+    pub activities_heart_intraday: Option<IntradayDataset<IntradayIntegral>>,
 }
 
-fn to_measurement<T: IntradayMeasurement>(
-    day: NaiveDate,
-    local_tz: Tz,
-    measurement: IntradayValue,
-    user_id: Uuid,
-) -> Result<T, Error> {
-    T::new(user_id, measurement.time_utc(day, local_tz)?, measurement)
-}
-
-pub fn measurement_for_day<T: IntradayMeasurement>(
+pub fn intraday_for_day<T: IntradayMeasurement>(
     day: NaiveDate,
     token: &Token,
-) -> Result<Vec<T>, Error> {
-    let client = reqwest::Client::new();
-
-    let tz = local_tz(token)?;
+    limiter: &RateLimiter,
+) -> Result<IntradayResponse, Error> {
     let endpoint = format!(
-        "https://api.fitbit.com/1/user/-/activities/{}/date/{}/1d/1min/time/00:00/23:59.json",
+        "https://api.fitbit.com/1/user/-/activities/{}/date/{}/1d/{}/time/00:00/23:59.json",
         T::name(),
-        day.format("%Y-%m-%d")
+        day.format("%Y-%m-%d"),
+        T::detail_level()
     );
 
-    let mut request = client
-        .get(&endpoint)
-        .bearer_auth(&token.access_token)
-        .send()
-        .map_err(error::ErrorInternalServerError)?;
+    get_json(&endpoint, token, limiter)
+}
 
-    let resp: IntradayResponse = request.json().map_err(error::ErrorInternalServerError)?;
+pub fn measurements_from_response<T: IntradayMeasurement>(
+    day: NaiveDate,
+    token: &Token,
+    resp: IntradayResponse,
+) -> Result<Vec<T>, Error> {
+    // the dataset is in the order it was recorded, which the clock needs to tell the two
+    // occurrences of the hour repeated when DST ends apart
+    let mut clock = LocalClock::new(local_tz(token)?);
+    let mut by_time = BTreeMap::new();
+    for value in T::parse_response(resp).unwrap_or_else(|| vec![]) {
+        match value.time_utc(day, &mut clock) {
+            Ok(time) => {
+                if by_time.insert(time, value).is_some() {
+                    warn!("Duplicate {} sample at {} on {}", T::name(), time, day);
+                }
+            }
+            Err(e) => warn!("Dropping {} sample on {}: {}", T::name(), day, e),
+        }
+    }
 
-    let measurements: Vec<T> = T::parse_response(resp)
-        .unwrap_or_else(|| vec![])
+    by_time
         .into_iter()
-        .map(|s| to_measurement(day, tz, s, token.user_id))
-        .filter(|s| s.is_ok())
-        .map(|s| s.expect("uh oh"))
-        .collect();
+        .map(|(time, value)| T::new(token.user_id, time, value))
+        .collect()
+}
 
-    Ok(measurements)
+pub fn measurement_for_day<T: IntradayMeasurement>(
+    day: NaiveDate,
+    token: &Token,
+    limiter: &RateLimiter,
+) -> Result<Vec<T>, Error> {
+    let resp = intraday_for_day::<T>(day, token, limiter)?;
+    measurements_from_response(day, token, resp)
 }
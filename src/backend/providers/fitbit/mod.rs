@@ -1,17 +1,29 @@
-use crate::db::Token;
-use crate::oauth::{OAuthError, OAuthProvider, OAuthToken};
+use crate::crypto::Keyring;
+use crate::db::{Token, UpdateToken};
+use crate::oauth::{check_refresh_response, OAuthError, OAuthProvider, OAuthState, OAuthToken};
+use crate::rate_limit::RateLimiter;
 use crate::utils::urlencode;
-use actix_web::Error;
-use chrono::{Duration, Utc};
+use actix_web::{error, Error};
+use chrono::offset::{FixedOffset, LocalResult, Offset, TimeZone};
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
 use chrono_tz::{Tz, US::Pacific};
-use reqwest;
+use diesel::pg::PgConnection;
+use reqwest::{self, header::HeaderMap, StatusCode};
+use serde::de::DeserializeOwned;
 
 pub static FITBIT_REDIRECT_URI: &'static str = "http://localhost:8080/oauth/fitbit/callback";
 pub static FITBIT_EXPIRATION_MS: i32 = 604800;
 
+pub mod body;
+pub mod heart;
 pub mod intraday;
+pub use crate::providers::fitbit::body::*;
+pub use crate::providers::fitbit::heart::*;
 pub use crate::providers::fitbit::intraday::*;
 
+pub mod sleep;
+pub use crate::providers::fitbit::sleep::*;
+
 pub struct Fitbit {
     oauth_id: String,
     oauth_secret: String,
@@ -45,17 +57,138 @@ impl From<FitbitCallbackResponse> for OAuthToken {
             scopes: fcr.scope.split(' ').map(String::from).collect(),
             email: None,
             expiration: Utc::now() + Duration::seconds(i64::from(fcr.expires_in)),
-            g_sub: None,
+            identity: None,
+            timezone: None,
+            utc_offset_ms: None,
         }
     }
 }
 
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FitbitProfile {
+    timezone: String,
+    #[serde(rename = "offsetFromUTCMillis")]
+    offset_from_utc_millis: i32,
+}
+
+#[derive(Deserialize)]
+pub struct FitbitProfileResponse {
+    user: FitbitProfile,
+}
+
+fn get_profile(access_token: &str) -> Result<FitbitProfile, OAuthError> {
+    let client = reqwest::Client::new();
+    let mut request = client
+        .get("https://api.fitbit.com/1/user/-/profile.json")
+        .bearer_auth(access_token)
+        .send()?;
+
+    let parsed: FitbitProfileResponse = request.json()?;
+    Ok(parsed.user)
+}
+
+/// Fills in the timezone from the user's Fitbit profile. A missing profile shouldn't stop anyone
+/// from connecting, so failures are only logged.
+fn with_profile(mut token: OAuthToken) -> OAuthToken {
+    match get_profile(&token.access_token) {
+        Ok(profile) => {
+            token.timezone = Some(profile.timezone);
+            token.utc_offset_ms = Some(profile.offset_from_utc_millis);
+        }
+        Err(e) => warn!("Couldn't fetch Fitbit profile for {}: {}", token.user_id, e),
+    }
+    token
+}
+
+/// Fills in the timezone of a token from before timezones were kept. Those are otherwise only
+/// picked up when the token is next refreshed, and until then its data would be read in the
+/// fallback timezone.
+pub fn with_stored_timezone(conn: &PgConnection, keyring: &Keyring, token: Token) -> Token {
+    if token.timezone.is_some() || token.utc_offset_ms.is_some() {
+        return token;
+    }
+
+    let profile = match get_profile(&token.access_token) {
+        Ok(profile) => profile,
+        Err(e) => {
+            warn!("Couldn't fetch Fitbit profile for {}: {}", token.user_id, e);
+            return token;
+        }
+    };
+    let update = UpdateToken {
+        access_token: None,
+        access_token_expiry: None,
+        service_userid: None,
+        refresh_token: None,
+        timezone: Some(&profile.timezone),
+        utc_offset_ms: Some(profile.offset_from_utc_millis),
+        scopes: None,
+    };
+    match Token::update(conn, keyring, token.id, &update) {
+        Ok(token) => token,
+        Err(e) => {
+            warn!("Couldn't store the timezone of token {}: {}", token.id, e);
+            Token {
+                timezone: Some(profile.timezone),
+                utc_offset_ms: Some(profile.offset_from_utc_millis),
+                ..token
+            }
+        }
+    }
+}
+
+/// Subscribes to every collection of the user's, so Fitbit notifies `/webhooks/fitbit` whenever
+/// new data is synced. Subscription ids only have to be unique per user, so it's their Fitbit id.
+fn subscribe(access_token: &str, fitbit_user_id: &str) -> Result<(), OAuthError> {
+    let client = reqwest::Client::new();
+    client
+        .post(&format!(
+            "https://api.fitbit.com/1/user/-/apiSubscriptions/{}.json",
+            fitbit_user_id
+        ))
+        .bearer_auth(access_token)
+        .send()?
+        .error_for_status()?;
+
+    Ok(())
+}
+
+fn unsubscribe(access_token: &str, fitbit_user_id: &str) -> Result<(), OAuthError> {
+    let client = reqwest::Client::new();
+    client
+        .delete(&format!(
+            "https://api.fitbit.com/1/user/-/apiSubscriptions/{}.json",
+            fitbit_user_id
+        ))
+        .bearer_auth(access_token)
+        .send()?
+        .error_for_status()?;
+
+    Ok(())
+}
+
+/// Like the profile, a missing subscription only means falling back to the scheduler's polling.
+fn with_subscription(token: OAuthToken) -> OAuthToken {
+    if let Err(e) = subscribe(&token.access_token, &token.user_id) {
+        warn!(
+            "Couldn't subscribe to Fitbit updates for {}: {}",
+            token.user_id, e
+        );
+    }
+    token
+}
+
 impl OAuthProvider for Fitbit {
     fn name(&self) -> &'static str {
         "fitbit"
     }
 
-    fn oauth_redirect_url(&self) -> Result<String, OAuthError> {
+    fn supports_pkce(&self) -> bool {
+        true
+    }
+
+    fn oauth_redirect_url(&self, state: &OAuthState) -> Result<String, OAuthError> {
         let scopes = [
             "activity",
             "heartrate",
@@ -65,49 +198,280 @@ impl OAuthProvider for Fitbit {
             "weight",
         ]
         .join(" ");
-        Ok(format!("https://www.fitbit.com/oauth2/authorize?response_type=code&client_id={}&redirect_uri={}&scope={}&expires_in={}",
+        let pkce = match state.code_challenge() {
+            Some(challenge) => format!(
+                "&code_challenge={}&code_challenge_method=S256",
+                urlencode(&challenge)
+            ),
+            None => "".to_string(),
+        };
+        Ok(format!("https://www.fitbit.com/oauth2/authorize?response_type=code&client_id={}&redirect_uri={}&scope={}&expires_in={}&state={}{}",
             urlencode(&self.oauth_id),
             urlencode(FITBIT_REDIRECT_URI),
             urlencode(&scopes),
             // 1 hour
-            FITBIT_EXPIRATION_MS))
+            FITBIT_EXPIRATION_MS,
+            urlencode(&state.state),
+            pkce))
     }
 
-    fn token_from_code(&self, code: &str) -> Result<OAuthToken, OAuthError> {
+    fn token_from_code(&self, code: &str, state: &OAuthState) -> Result<OAuthToken, OAuthError> {
         let client = reqwest::Client::new();
+        let redirect_uri = urlencode(FITBIT_REDIRECT_URI);
+        let mut form = vec![
+            ("clientId", self.oauth_id.as_str()),
+            ("grant_type", "authorization_code"),
+            ("redirect_uri", redirect_uri.as_str()),
+            ("code", code),
+        ];
+        if let Some(verifier) = &state.code_verifier {
+            form.push(("code_verifier", verifier.as_str()));
+        }
+
         let mut request = client
             .post("https://api.fitbit.com/oauth2/token")
             .basic_auth(&self.oauth_id, Some(&self.oauth_secret))
-            .form(&[
-                ("clientId", self.oauth_id.as_str()),
-                ("grant_type", "authorization_code"),
-                ("redirect_uri", &urlencode(FITBIT_REDIRECT_URI)),
-                ("code", code),
-            ])
+            .form(&form)
             .send()?;
 
         let parsed: FitbitCallbackResponse = request.json()?;
-        Ok(OAuthToken::from(parsed))
+        Ok(with_subscription(with_profile(OAuthToken::from(parsed))))
     }
 
     fn refresh_token(&self, token: OAuthToken) -> Result<OAuthToken, OAuthError> {
         let client = reqwest::Client::new();
-        let mut request = client
-            .post("https://api.fitbit.com/oauth2/token")
-            .basic_auth(&self.oauth_id, Some(&self.oauth_secret))
-            .form(&[
-                ("clientId", self.oauth_id.as_str()),
-                ("grant_type", "refresh_token"),
-                ("refresh_token", &token.refresh_token),
-            ])
-            .send()?;
+        let mut request = check_refresh_response(
+            client
+                .post("https://api.fitbit.com/oauth2/token")
+                .basic_auth(&self.oauth_id, Some(&self.oauth_secret))
+                .form(&[
+                    ("clientId", self.oauth_id.as_str()),
+                    ("grant_type", "refresh_token"),
+                    ("refresh_token", &token.refresh_token),
+                ])
+                .send()?,
+        )?;
 
         let parsed: FitbitCallbackResponse = request.json()?;
-        Ok(OAuthToken::from(parsed))
+        // pick up timezone changes (travel) every time the token is refreshed
+        Ok(with_profile(OAuthToken::from(parsed)))
+    }
+
+    fn revoke_token(&self, token: &Token) -> Result<(), OAuthError> {
+        // revoking doesn't remove the subscription, and it can't be removed without a token
+        if let Err(e) = unsubscribe(&token.access_token, &token.service_userid) {
+            warn!(
+                "Couldn't unsubscribe {} from Fitbit updates: {}",
+                token.service_userid, e
+            );
+        }
+
+        // revoking the refresh token revokes every access token issued with it
+        let client = reqwest::Client::new();
+        client
+            .post("https://api.fitbit.com/oauth2/revoke")
+            .basic_auth(&self.oauth_id, Some(&self.oauth_secret))
+            .form(&[("token", token.refresh_token.as_str())])
+            .send()?
+            .error_for_status()?;
+
+        Ok(())
     }
 }
 
-pub fn local_tz(_token: &Token) -> Result<Tz, Error> {
-    // TODO
-    Ok(Pacific)
+fn header_number(headers: &HeaderMap, name: &str) -> Option<i64> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+}
+
+/// GETs a Fitbit API endpoint on the user's behalf, within their rate limit. Fitbit's own count
+/// comes back with every response and replaces ours, and a 429 blocks the user until the window
+/// resets. Either way, running out fails with `RateLimited`.
+pub fn get_json<T: DeserializeOwned>(
+    endpoint: &str,
+    token: &Token,
+    limiter: &RateLimiter,
+) -> Result<T, Error> {
+    limiter.acquire(&token.user_id)?;
+
+    let client = reqwest::Client::new();
+    let mut response = client
+        .get(endpoint)
+        .bearer_auth(&token.access_token)
+        .send()
+        .map_err(error::ErrorInternalServerError)?;
+
+    let headers = response.headers();
+    let reset = header_number(headers, "fitbit-rate-limit-reset")
+        // Retry-After is the same number, but only sent with a 429
+        .or_else(|| header_number(headers, "retry-after"))
+        .unwrap_or(60 * 60);
+
+    if response.status() == StatusCode::TOO_MANY_REQUESTS {
+        return Err(limiter.block(&token.user_id, Utc::now() + Duration::seconds(reset)));
+    }
+
+    if let Some(remaining) = header_number(headers, "fitbit-rate-limit-remaining") {
+        let recorded =
+            limiter.record_remaining(&token.user_id, remaining as u32, Duration::seconds(reset));
+        if let Err(e) = recorded {
+            warn!(
+                "Couldn't record Fitbit rate limit for {}: {}",
+                token.user_id, e
+            );
+        }
+    }
+
+    response
+        .error_for_status()
+        .map_err(error::ErrorInternalServerError)?
+        .json()
+        .map_err(error::ErrorInternalServerError)
+}
+
+/// The timezone Fitbit reports the user's data in. Falls back to the profile's UTC offset if the
+/// name isn't one we know, and to US Pacific, which everything was read in before timezones were
+/// kept, if the profile couldn't be fetched.
+pub fn local_tz(token: &Token) -> Result<Tz, Error> {
+    if let Some(tz) = token.timezone.as_ref().and_then(|tz| tz.parse::<Tz>().ok()) {
+        return Ok(tz);
+    }
+
+    // Etc/GMT zones have inverted signs, ie Etc/GMT+8 is UTC-8
+    let offset_tz = token
+        .utc_offset_ms
+        .filter(|ms| ms % 3_600_000 == 0)
+        .and_then(|ms| format!("Etc/GMT{:+}", -ms / 3_600_000).parse::<Tz>().ok());
+
+    Ok(offset_tz.unwrap_or_else(|| {
+        warn!(
+            "No usable timezone for token {}, assuming US/Pacific",
+            token.id
+        );
+        Pacific
+    }))
+}
+
+/// Converts a local wall-clock time to UTC on its own. Ambiguous times (DST ending) resolve to
+/// the earlier instant, and times that don't exist (the hour skipped when DST starts) are read with
+/// the offset from just before the transition, instead of being dropped.
+pub fn local_to_utc(naive_dt: &NaiveDateTime, local_tz: Tz) -> Option<DateTime<Utc>> {
+    LocalClock::new(local_tz).to_utc(naive_dt)
+}
+
+/// Converts a sequence of local wall-clock times, in the order they were recorded, to UTC, giving
+/// every sample its own instant across DST transitions:
+///
+/// - When DST ends, the repeated hour is read as the earlier instant until the wall clock goes
+///   back, and as the later one after that.
+/// - A time in the hour skipped when DST starts means the device's clock hadn't moved forward
+///   yet. That sample and every one after it are read with the offset from before the
+///   transition, so they don't land on the instants of the samples an hour later.
+pub struct LocalClock {
+    tz: Tz,
+    last: Option<NaiveDateTime>,
+    repeating: bool,
+    behind: Option<FixedOffset>,
+}
+
+impl LocalClock {
+    pub fn new(tz: Tz) -> LocalClock {
+        LocalClock {
+            tz,
+            last: None,
+            repeating: false,
+            behind: None,
+        }
+    }
+
+    pub fn to_utc(&mut self, naive_dt: &NaiveDateTime) -> Option<DateTime<Utc>> {
+        if self.last.map_or(false, |last| *naive_dt <= last) {
+            self.repeating = true;
+        }
+        self.last = Some(*naive_dt);
+
+        if let Some(offset) = self.behind {
+            return offset
+                .from_local_datetime(naive_dt)
+                .single()
+                .map(|dt| dt.with_timezone(&Utc));
+        }
+
+        match self.tz.from_local_datetime(naive_dt) {
+            LocalResult::Single(dt) => Some(dt.with_timezone(&Utc)),
+            LocalResult::Ambiguous(earliest, latest) => {
+                let dt = if self.repeating { latest } else { earliest };
+                Some(dt.with_timezone(&Utc))
+            }
+            LocalResult::None => {
+                let offset = self
+                    .tz
+                    .from_local_datetime(&(*naive_dt - Duration::hours(1)))
+                    .earliest()?
+                    .offset()
+                    .fix();
+                self.behind = Some(offset);
+                offset
+                    .from_local_datetime(naive_dt)
+                    .single()
+                    .map(|dt| dt.with_timezone(&Utc))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LocalClock;
+    use chrono::{NaiveDate, TimeZone, Utc};
+    use chrono_tz::US::Pacific;
+
+    fn convert(day: NaiveDate, times: &[(u32, u32)]) -> Vec<chrono::DateTime<Utc>> {
+        let mut clock = LocalClock::new(Pacific);
+        times
+            .iter()
+            .map(|&(h, m)| clock.to_utc(&day.and_hms(h, m, 0)).unwrap())
+            .collect()
+    }
+
+    fn assert_distinct_and_increasing(instants: &[chrono::DateTime<Utc>]) {
+        for pair in instants.windows(2) {
+            assert!(pair[0] < pair[1], "{} isn't before {}", pair[0], pair[1]);
+        }
+    }
+
+    #[test]
+    fn skipped_hour_gets_its_own_instants() {
+        // clocks went from 01:59:59 PST straight to 03:00:00 PDT
+        let day = NaiveDate::from_ymd(2019, 3, 10);
+        let instants = convert(day, &[(1, 59), (2, 0), (2, 30), (3, 0), (3, 30)]);
+
+        assert_distinct_and_increasing(&instants);
+        assert_eq!(instants[0], Utc.ymd(2019, 3, 10).and_hms(9, 59, 0));
+        assert_eq!(instants[1], Utc.ymd(2019, 3, 10).and_hms(10, 0, 0));
+        assert_eq!(instants[3], Utc.ymd(2019, 3, 10).and_hms(11, 0, 0));
+    }
+
+    #[test]
+    fn skipped_hour_without_samples_is_unaffected() {
+        let day = NaiveDate::from_ymd(2019, 3, 10);
+        let instants = convert(day, &[(1, 59), (3, 0)]);
+
+        assert_eq!(instants[0], Utc.ymd(2019, 3, 10).and_hms(9, 59, 0));
+        assert_eq!(instants[1], Utc.ymd(2019, 3, 10).and_hms(10, 0, 0));
+    }
+
+    #[test]
+    fn repeated_hour_gets_both_instants_in_order() {
+        // clocks went from 01:59:59 PDT back to 01:00:00 PST
+        let day = NaiveDate::from_ymd(2019, 11, 3);
+        let instants = convert(day, &[(0, 59), (1, 0), (1, 30), (1, 0), (1, 30), (2, 0)]);
+
+        assert_distinct_and_increasing(&instants);
+        assert_eq!(instants[1], Utc.ymd(2019, 11, 3).and_hms(8, 0, 0));
+        assert_eq!(instants[3], Utc.ymd(2019, 11, 3).and_hms(9, 0, 0));
+        assert_eq!(instants[5], Utc.ymd(2019, 11, 3).and_hms(10, 0, 0));
+    }
 }
@@ -0,0 +1,100 @@
+use super::{get_json, local_to_utc, local_tz};
+use crate::db::{Sleep, SleepStage, Token};
+use crate::rate_limit::RateLimiter;
+use actix_web::{error, Error};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use chrono_tz::Tz;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SleepLevelSegment {
+    pub date_time: String,
+    pub level: String,
+    pub seconds: i32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SleepLevels {
+    // `shortData` holds short wakes that overlap `data`, so we only keep the long segments
+    pub data: Vec<SleepLevelSegment>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SleepLog {
+    pub log_id: i64,
+    pub start_time: String,
+    pub end_time: String,
+    pub efficiency: i32,
+    pub is_main_sleep: bool,
+    pub minutes_asleep: i32,
+    pub minutes_awake: i32,
+    pub time_in_bed: i32,
+    pub levels: Option<SleepLevels>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SleepResponse {
+    pub sleep: Vec<SleepLog>,
+}
+
+fn time_utc(timestamp: &str, local_tz: Tz) -> Result<DateTime<Utc>, Error> {
+    let naive_dt = NaiveDateTime::parse_from_str(timestamp, "%Y-%m-%dT%H:%M:%S%.f")
+        .map_err(error::ErrorInternalServerError)?;
+
+    local_to_utc(&naive_dt, local_tz)
+        .ok_or_else(|| error::ErrorInternalServerError("error converting timestamp"))
+}
+
+fn to_sleep(log: SleepLog, local_tz: Tz, token: &Token) -> Result<(Sleep, Vec<SleepStage>), Error> {
+    let sleep = Sleep {
+        start_time: time_utc(&log.start_time, local_tz)?,
+        end_time: time_utc(&log.end_time, local_tz)?,
+        user_id: token.user_id,
+        source: "fitbit".to_string(),
+        log_id: log.log_id,
+        is_main_sleep: log.is_main_sleep,
+        efficiency: log.efficiency,
+        minutes_asleep: log.minutes_asleep,
+        minutes_awake: log.minutes_awake,
+        time_in_bed: log.time_in_bed,
+    };
+
+    let stages = log
+        .levels
+        .map(|l| l.data)
+        .unwrap_or_else(|| vec![])
+        .into_iter()
+        .map(|segment| {
+            Ok(SleepStage {
+                time: time_utc(&segment.date_time, local_tz)?,
+                user_id: token.user_id,
+                source: "fitbit".to_string(),
+                level: segment.level,
+                seconds: segment.seconds,
+            })
+        })
+        .collect::<Result<Vec<SleepStage>, Error>>()?;
+
+    Ok((sleep, stages))
+}
+
+/// Every sleep log that ended on `day` (Fitbit's `dateOfSleep`), along with its stages.
+pub fn sleep_for_day(
+    day: NaiveDate,
+    token: &Token,
+    limiter: &RateLimiter,
+) -> Result<Vec<(Sleep, Vec<SleepStage>)>, Error> {
+    let tz = local_tz(token)?;
+    let endpoint = format!(
+        "https://api.fitbit.com/1.2/user/-/sleep/date/{}.json",
+        day.format("%Y-%m-%d")
+    );
+
+    let resp: SleepResponse = get_json(&endpoint, token, limiter)?;
+
+    resp.sleep
+        .into_iter()
+        .map(|log| to_sleep(log, tz, token))
+        .collect()
+}
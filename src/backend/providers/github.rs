@@ -0,0 +1,161 @@
+use chrono::{Duration, Utc};
+use reqwest::{self, header::USER_AGENT};
+use serde::de::DeserializeOwned;
+use serde_json::json;
+
+use crate::db::Token;
+use crate::oauth::{OAuthError, OAuthProvider, OAuthState, OAuthToken};
+use crate::utils::urlencode;
+
+pub static GITHUB_REDIRECT_URI: &'static str = "http://localhost:8080/oauth/github/callback";
+/// GitHub's API turns down requests without a user agent.
+static GITHUB_USER_AGENT: &'static str = "qs";
+/// OAuth app tokens don't expire until they're revoked, but tokens need an expiry.
+static GITHUB_TOKEN_YEARS: i64 = 10;
+
+pub struct GitHub {
+    oauth_id: String,
+    oauth_secret: String,
+}
+
+impl GitHub {
+    pub fn new(oauth_id: &str, oauth_secret: &str) -> GitHub {
+        GitHub {
+            oauth_id: oauth_id.to_owned(),
+            oauth_secret: oauth_secret.to_owned(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct GitHubCallbackResponse {
+    access_token: Option<String>,
+    // comma separated, unlike everyone else's
+    scope: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct GitHubUser {
+    id: i64,
+}
+
+#[derive(Deserialize)]
+struct GitHubEmail {
+    email: String,
+    primary: bool,
+    verified: bool,
+}
+
+fn get_json<T: DeserializeOwned>(endpoint: &str, access_token: &str) -> Result<T, OAuthError> {
+    let client = reqwest::Client::new();
+    let mut request = client
+        .get(endpoint)
+        .header(USER_AGENT, GITHUB_USER_AGENT)
+        .bearer_auth(access_token)
+        .send()?
+        .error_for_status()?;
+
+    Ok(request.json()?)
+}
+
+/// The user's primary email, if they verified it. The one on their profile is whatever they chose
+/// to make public, if anything.
+fn primary_email(access_token: &str) -> Result<Option<String>, OAuthError> {
+    let emails: Vec<GitHubEmail> = get_json("https://api.github.com/user/emails", access_token)?;
+
+    Ok(emails
+        .into_iter()
+        .find(|email| email.primary && email.verified)
+        .map(|email| email.email))
+}
+
+impl OAuthProvider for GitHub {
+    fn name(&self) -> &'static str {
+        "github"
+    }
+
+    fn oauth_redirect_url(&self, state: &OAuthState) -> Result<String, OAuthError> {
+        let scopes = ["read:user", "user:email"].join(" ");
+        Ok(format!(
+            "https://github.com/login/oauth/authorize?client_id={}&redirect_uri={}&scope={}&state={}",
+            urlencode(&self.oauth_id),
+            urlencode(GITHUB_REDIRECT_URI),
+            urlencode(&scopes),
+            urlencode(&state.state)
+        ))
+    }
+
+    fn token_from_code(&self, code: &str, state: &OAuthState) -> Result<OAuthToken, OAuthError> {
+        let client = reqwest::Client::new();
+        let mut request = client
+            .post("https://github.com/login/oauth/access_token")
+            .header(reqwest::header::ACCEPT, "application/json")
+            .form(&[
+                ("client_id", self.oauth_id.as_str()),
+                ("client_secret", self.oauth_secret.as_str()),
+                ("redirect_uri", GITHUB_REDIRECT_URI),
+                ("code", code),
+                ("state", state.state.as_str()),
+            ])
+            .send()?;
+
+        // errors come back as 200s
+        let parsed: GitHubCallbackResponse = request.json()?;
+        let access_token = match (parsed.access_token, parsed.error) {
+            (Some(access_token), None) => access_token,
+            (_, error) => {
+                return Err(OAuthError::TokenError(
+                    parsed
+                        .error_description
+                        .or(error)
+                        .unwrap_or_else(|| "no access token".to_owned()),
+                ));
+            }
+        };
+
+        let user: GitHubUser = get_json("https://api.github.com/user", &access_token)?;
+        let email = primary_email(&access_token)?;
+
+        Ok(OAuthToken {
+            service: "github".to_string(),
+            access_token,
+            refresh_token: "".to_string(),
+            user_id: user.id.to_string(),
+            identity: Some(user.id.to_string()),
+            email,
+            scopes: parsed
+                .scope
+                .unwrap_or_default()
+                .split(',')
+                .map(String::from)
+                .collect(),
+            expiration: Utc::now() + Duration::days(365 * GITHUB_TOKEN_YEARS),
+            timezone: None,
+            utc_offset_ms: None,
+        })
+    }
+
+    fn refresh_token(&self, _token: OAuthToken) -> Result<OAuthToken, OAuthError> {
+        Err(OAuthError::TokenError(
+            "GitHub tokens can't be refreshed".to_owned(),
+        ))
+    }
+
+    fn revoke_token(&self, token: &Token) -> Result<(), OAuthError> {
+        let client = reqwest::Client::new();
+        client
+            .delete(&format!(
+                "https://api.github.com/applications/{}/grant",
+                self.oauth_id
+            ))
+            .header(USER_AGENT, GITHUB_USER_AGENT)
+            .basic_auth(&self.oauth_id, Some(&self.oauth_secret))
+            .json(&json!({ "access_token": token.access_token }))
+            .send()?
+            .error_for_status()?;
+
+        Ok(())
+    }
+}
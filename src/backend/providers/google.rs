@@ -1,18 +1,26 @@
-use jsonwebtoken::{dangerous_unsafe_decode, TokenData};
+use jsonwebtoken::{decode, decode_header, Algorithm, TokenData, Validation};
+use sha2::{Digest, Sha256};
+use std::sync::RwLock;
 use time::Duration;
-use uuid::Uuid;
 
-use crate::oauth::{OAuthError, OAuthProvider, OAuthToken};
+use crate::db::Token;
+use crate::oauth::{check_refresh_response, OAuthError, OAuthProvider, OAuthState, OAuthToken};
 use crate::utils::urlencode;
 
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use reqwest;
 
 pub static GOOGLE_REDIRECT_URI: &'static str = "http://localhost:8080/oauth/google/callback";
+/// Google rotates its signing keys every few days, and announces new ones well ahead of time.
+pub static GOOGLE_JWKS_CACHE_MINUTES: i64 = 60;
+/// How long after a fetch an unknown key id is just rejected, instead of fetching the keys again.
+pub static GOOGLE_JWKS_MIN_REFETCH_SECONDS: i64 = 60;
+static GOOGLE_ISSUERS: [&'static str; 2] = ["accounts.google.com", "https://accounts.google.com"];
 
 pub struct Google {
     oauth_id: String,
     oauth_secret: String,
+    jwks: RwLock<Option<(Jwks, DateTime<Utc>)>>,
 }
 
 impl Google {
@@ -20,6 +28,7 @@ impl Google {
         Google {
             oauth_id: oauth_id.to_owned(),
             oauth_secret: oauth_secret.to_owned(),
+            jwks: RwLock::new(None),
         }
     }
 }
@@ -35,32 +44,70 @@ pub struct GoogleCallbackResponse {
 
 #[derive(Deserialize)]
 struct GoogleProfileClaims {
-    // TODO: use the access token hash to verify
-    // at_hash: String,
+    iss: String,
+    at_hash: Option<String>,
     email: String,
     sub: String,
 }
 
-impl From<GoogleCallbackResponse> for OAuthToken {
-    fn from(gcr: GoogleCallbackResponse) -> Self {
-        let TokenData { claims, .. } =
-            dangerous_unsafe_decode::<GoogleProfileClaims>(&gcr.id_token)
-                .expect("Bad Google response!");
+#[derive(Deserialize, Clone)]
+struct Jwk {
+    kid: String,
+    // base64url big-endian modulus and exponent
+    n: String,
+    e: String,
+}
 
-        OAuthToken {
-            service: "google".to_string(),
-            access_token: gcr.access_token,
-            refresh_token: match gcr.refresh_token {
-                Some(token) => token,
-                // TODO throw an error? idk
-                None => "".to_string(),
-            },
-            user_id: claims.sub.clone(),
-            g_sub: Some(claims.sub.clone()),
-            email: Some(claims.email),
-            scopes: gcr.scope.split(' ').map(String::from).collect(),
-            expiration: Utc::now() + Duration::seconds(i64::from(gcr.expires_in)),
-        }
+#[derive(Deserialize, Clone)]
+struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+fn invalid(reason: &str) -> OAuthError {
+    OAuthError::InvalidIdToken(reason.to_owned())
+}
+
+/// DER for one ASN.1 INTEGER, as a positive number.
+fn der_integer(bytes: &[u8]) -> Vec<u8> {
+    let mut value: Vec<u8> = bytes.iter().cloned().skip_while(|&b| b == 0).collect();
+    if value.first().map_or(true, |&b| b & 0x80 != 0) {
+        value.insert(0, 0);
+    }
+    let mut der = vec![0x02];
+    der.extend(der_length(value.len()));
+    der.extend(value);
+    der
+}
+
+fn der_length(len: usize) -> Vec<u8> {
+    if len < 0x80 {
+        return vec![len as u8];
+    }
+    let bytes: Vec<u8> = len
+        .to_be_bytes()
+        .iter()
+        .cloned()
+        .skip_while(|&b| b == 0)
+        .collect();
+    let mut der = vec![0x80 | bytes.len() as u8];
+    der.extend(bytes);
+    der
+}
+
+impl Jwk {
+    /// The key as a PKCS#1 `RSAPublicKey`, which is what jsonwebtoken verifies RS256 with.
+    fn rsa_public_key_der(&self) -> Result<Vec<u8>, OAuthError> {
+        let n = base64::decode_config(&self.n, base64::URL_SAFE_NO_PAD)
+            .map_err(|_| invalid("malformed JWK modulus"))?;
+        let e = base64::decode_config(&self.e, base64::URL_SAFE_NO_PAD)
+            .map_err(|_| invalid("malformed JWK exponent"))?;
+
+        let mut body = der_integer(&n);
+        body.extend(der_integer(&e));
+        let mut der = vec![0x30];
+        der.extend(der_length(body.len()));
+        der.extend(body);
+        Ok(der)
     }
 }
 
@@ -69,8 +116,8 @@ pub struct EndpointInfo {
     authorization_endpoint: String,
     token_endpoint: String,
     // userinfo_endpoint: String,
-    // revocation_endpoint: String,
-    // jwks_uri: String
+    revocation_endpoint: String,
+    jwks_uri: String,
 }
 
 fn get_discovery_doc() -> Result<EndpointInfo, OAuthError> {
@@ -82,15 +129,117 @@ fn get_discovery_doc() -> Result<EndpointInfo, OAuthError> {
     Ok(res)
 }
 
+fn get_jwks() -> Result<Jwks, OAuthError> {
+    let client = reqwest::Client::new();
+    let res: Jwks = client
+        .get(&get_discovery_doc()?.jwks_uri)
+        .send()?
+        .error_for_status()?
+        .json()?;
+    Ok(res)
+}
+
+fn find_signing_key(jwks: &Jwks, kid: &str) -> Option<Jwk> {
+    jwks.keys.iter().find(|key| key.kid == kid).cloned()
+}
+
+/// The answer for `kid` from the cache, or `None` if the keys should be refetched first.
+fn cached_signing_key(
+    cache: &Option<(Jwks, DateTime<Utc>)>,
+    kid: &str,
+) -> Option<Result<Jwk, OAuthError>> {
+    let (jwks, fetched_at) = cache.as_ref()?;
+    let age = Utc::now() - *fetched_at;
+    match find_signing_key(jwks, kid) {
+        Some(key) if age < chrono::Duration::minutes(GOOGLE_JWKS_CACHE_MINUTES) => Some(Ok(key)),
+        key if age < chrono::Duration::seconds(GOOGLE_JWKS_MIN_REFETCH_SECONDS) => {
+            Some(key.ok_or_else(|| invalid("unknown signing key")))
+        }
+        _ => None,
+    }
+}
+
+impl Google {
+    /// The signing key with id `kid`. The cached keys are refetched when they're old, or when
+    /// they don't have `kid` (Google started signing with a new key), but not more often than
+    /// `GOOGLE_JWKS_MIN_REFETCH_SECONDS` allows, so made up key ids can't have every request call
+    /// Google.
+    fn signing_key(&self, kid: &str) -> Result<Jwk, OAuthError> {
+        let poisoned = || OAuthError::Error("the JWKS cache is poisoned".to_owned());
+
+        if let Some(key) = cached_signing_key(&*self.jwks.read().map_err(|_| poisoned())?, kid) {
+            return key;
+        }
+
+        // checked again, another request may have refetched while this one waited for the lock
+        let mut cache = self.jwks.write().map_err(|_| poisoned())?;
+        if let Some(key) = cached_signing_key(&*cache, kid) {
+            return key;
+        }
+        let jwks = get_jwks()?;
+        let key = find_signing_key(&jwks, kid);
+        *cache = Some((jwks, Utc::now()));
+        key.ok_or_else(|| invalid("unknown signing key"))
+    }
+
+    /// Checks the id_token's signature, audience, issuer, expiry and access token hash before
+    /// trusting anything in it.
+    fn verify_id_token(
+        &self,
+        id_token: &str,
+        access_token: &str,
+    ) -> Result<GoogleProfileClaims, OAuthError> {
+        let header = decode_header(id_token).map_err(|_| invalid("malformed id_token"))?;
+        let kid = header.kid.ok_or_else(|| invalid("id_token has no key id"))?;
+        let key = self.signing_key(&kid)?.rsa_public_key_der()?;
+
+        let mut validation = Validation::new(Algorithm::RS256);
+        validation.set_audience(&self.oauth_id);
+        let TokenData { claims, .. } = decode::<GoogleProfileClaims>(id_token, &key, &validation)
+            .map_err(|e| invalid(&e.to_string()))?;
+
+        if !GOOGLE_ISSUERS.contains(&claims.iss.as_str()) {
+            return Err(invalid("wrong issuer"));
+        }
+
+        // at_hash is the left half of the access token's SHA-256
+        let digest = Sha256::digest(access_token.as_bytes());
+        let at_hash = base64::encode_config(&digest[..digest.len() / 2], base64::URL_SAFE_NO_PAD);
+        match &claims.at_hash {
+            Some(hash) if *hash != at_hash => Err(invalid("access token hash mismatch")),
+            _ => Ok(claims),
+        }
+    }
+
+    fn token_from_response(&self, gcr: GoogleCallbackResponse) -> Result<OAuthToken, OAuthError> {
+        let claims = self.verify_id_token(&gcr.id_token, &gcr.access_token)?;
+
+        Ok(OAuthToken {
+            service: "google".to_string(),
+            access_token: gcr.access_token,
+            refresh_token: match gcr.refresh_token {
+                Some(token) => token,
+                // TODO throw an error? idk
+                None => "".to_string(),
+            },
+            user_id: claims.sub.clone(),
+            identity: Some(claims.sub.clone()),
+            email: Some(claims.email),
+            scopes: gcr.scope.split(' ').map(String::from).collect(),
+            expiration: Utc::now() + Duration::seconds(i64::from(gcr.expires_in)),
+            timezone: None,
+            utc_offset_ms: None,
+        })
+    }
+}
+
 impl OAuthProvider for Google {
     fn name(&self) -> &'static str {
         "google"
     }
 
-    fn oauth_redirect_url(&self) -> Result<String, OAuthError> {
+    fn oauth_redirect_url(&self, state: &OAuthState) -> Result<String, OAuthError> {
         let scopes = ["openid", "email"].join(" ");
-        // todo a real state/session cookie
-        let state = format!("{}", Uuid::new_v4());
         let authorization_endpoint = get_discovery_doc()?.authorization_endpoint;
         Ok(format!(
             "{}?response_type=code&client_id={}&redirect_uri={}&scope={}&state={}&access_type=offline",
@@ -98,11 +247,11 @@ impl OAuthProvider for Google {
             urlencode(&self.oauth_id),
             urlencode(GOOGLE_REDIRECT_URI),
             urlencode(&scopes),
-            urlencode(state.as_str())
+            urlencode(&state.state)
         ))
     }
 
-    fn token_from_code(&self, code: &str) -> Result<OAuthToken, OAuthError> {
+    fn token_from_code(&self, code: &str, _state: &OAuthState) -> Result<OAuthToken, OAuthError> {
         let client = reqwest::Client::new();
         let token_endpoint = get_discovery_doc()?.token_endpoint;
 
@@ -118,27 +267,44 @@ impl OAuthProvider for Google {
             .send()?;
 
         let parsed: GoogleCallbackResponse = request.json()?;
-        let token = OAuthToken::from(parsed);
-
-        Ok(token)
+        self.token_from_response(parsed)
     }
 
     fn refresh_token(&self, token: OAuthToken) -> Result<OAuthToken, OAuthError> {
         let client = reqwest::Client::new();
         let token_endpoint = get_discovery_doc()?.token_endpoint;
 
-        let mut request = client
-            .post(&token_endpoint)
-            .form(&[
-                ("client_id", self.oauth_id.as_str()),
-                ("client_secret", self.oauth_secret.as_str()),
-                ("grant_type", "refresh_token"),
-                ("refresh_token", &token.refresh_token),
-            ])
-            .send()?;
+        let mut request = check_refresh_response(
+            client
+                .post(&token_endpoint)
+                .form(&[
+                    ("client_id", self.oauth_id.as_str()),
+                    ("client_secret", self.oauth_secret.as_str()),
+                    ("grant_type", "refresh_token"),
+                    ("refresh_token", &token.refresh_token),
+                ])
+                .send()?,
+        )?;
 
         let parsed: GoogleCallbackResponse = request.json()?;
+        self.token_from_response(parsed)
+    }
+
+    fn revoke_token(&self, token: &Token) -> Result<(), OAuthError> {
+        let client = reqwest::Client::new();
+        let revocation_endpoint = get_discovery_doc()?.revocation_endpoint;
+
+        // Google only hands out a refresh token the first time, so it might be empty
+        let revoked = match token.refresh_token.as_str() {
+            "" => token.access_token.as_str(),
+            refresh_token => refresh_token,
+        };
+        client
+            .post(&revocation_endpoint)
+            .form(&[("token", revoked)])
+            .send()?
+            .error_for_status()?;
 
-        Ok(OAuthToken::from(parsed))
+        Ok(())
     }
 }
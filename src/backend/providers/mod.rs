@@ -1,2 +1,3 @@
 pub mod fitbit;
+pub mod github;
 pub mod google;
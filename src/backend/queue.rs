@@ -1,14 +1,30 @@
+use crate::db::Job;
 use crate::providers::fitbit::IntradayMetric;
-use chrono::NaiveDate;
+use actix_web::{error, Error};
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use diesel::pg::PgConnection;
 pub use oppgave::Queue;
-use redis::Client;
+use redis::{Client, Commands};
+use std::collections::HashMap;
 use uuid::Uuid;
 
+/// First retry is after this many seconds, doubling with every attempt after that.
+pub static RETRY_BASE_SECONDS: i64 = 30;
+pub static RETRY_MAX_SECONDS: i64 = 60 * 60;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum QueueActionParams {
     IngestIntraday(IntradayMetric, NaiveDate),
     // startDate, num_days
     BulkIngestIntraday(IntradayMetric, NaiveDate, u32),
+    IngestSleep(NaiveDate),
+    // startDate, num_days
+    BulkIngestSleep(NaiveDate, u32),
+    // startDate, endDate (inclusive)
+    IngestBody(NaiveDate, NaiveDate),
+    // from the day after the metric's sync cursor through today, and holes before the cursor if
+    // the flag is set
+    SyncSince(IntradayMetric, bool),
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -16,9 +32,245 @@ pub struct QueueAction {
     pub id: Uuid,
     pub user_id: Uuid,
     pub params: QueueActionParams,
+    /// How many times this action has failed so far
+    #[serde(default)]
+    pub attempts: u32,
+    /// The bulk action that enqueued this one
+    #[serde(default)]
+    pub parent_id: Option<Uuid>,
+}
+
+impl QueueActionParams {
+    /// The service the action ingests from.
+    pub fn service(&self) -> &'static str {
+        match self {
+            QueueActionParams::IngestIntraday(..)
+            | QueueActionParams::BulkIngestIntraday(..)
+            | QueueActionParams::IngestSleep(..)
+            | QueueActionParams::BulkIngestSleep(..)
+            | QueueActionParams::IngestBody(..)
+            | QueueActionParams::SyncSince(..) => "fitbit",
+        }
+    }
+}
+
+impl QueueAction {
+    pub fn new(user_id: Uuid, params: QueueActionParams) -> QueueAction {
+        QueueAction {
+            id: Uuid::new_v4(),
+            user_id,
+            params,
+            attempts: 0,
+            parent_id: None,
+        }
+    }
+
+    pub fn child(&self, params: QueueActionParams) -> QueueAction {
+        QueueAction {
+            parent_id: Some(self.id),
+            ..QueueAction::new(self.user_id, params)
+        }
+    }
+}
+
+/// An action that failed too many times, along with the last error.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeadLetter {
+    pub action: QueueAction,
+    pub error: String,
+    pub failed_at: DateTime<Utc>,
 }
 
 pub fn init_queue(redis_url: &str, queue_name: String) -> Queue {
     let client = Client::open(redis_url).expect("Failed to connect to redis");
     Queue::new(queue_name, client)
 }
+
+/// Failed actions live next to the queue in redis: `<queue>:delayed` is a sorted set of actions
+/// waiting to be retried, scored by when they're due, and `<queue>:dlq` is a hash of dead letters
+/// keyed by action id.
+pub struct RetryQueue {
+    client: Client,
+    queue_name: String,
+    max_attempts: u32,
+}
+
+impl RetryQueue {
+    pub fn new(redis_url: &str, queue_name: String, max_attempts: u32) -> RetryQueue {
+        let client = Client::open(redis_url).expect("Failed to connect to redis");
+        RetryQueue {
+            client,
+            queue_name,
+            max_attempts,
+        }
+    }
+
+    fn delayed_key(&self) -> String {
+        format!("{}:delayed", self.queue_name)
+    }
+
+    fn dlq_key(&self) -> String {
+        format!("{}:dlq", self.queue_name)
+    }
+
+    fn backoff(attempts: u32) -> Duration {
+        let secs = (0..attempts.saturating_sub(1)).fold(RETRY_BASE_SECONDS, |secs, _| {
+            (secs * 2).min(RETRY_MAX_SECONDS)
+        });
+        Duration::seconds(secs)
+    }
+
+    /// Records a failed attempt: the action is retried after a backoff, or dead-lettered once it
+    /// has used up its attempts. Returns when it'll be retried, if it will.
+    pub fn fail(&self, mut action: QueueAction, err: &str) -> Result<Option<DateTime<Utc>>, Error> {
+        let conn = self
+            .client
+            .get_connection()
+            .map_err(error::ErrorInternalServerError)?;
+        action.attempts += 1;
+
+        if action.attempts >= self.max_attempts {
+            let dead_letter = DeadLetter {
+                action,
+                error: err.to_string(),
+                failed_at: Utc::now(),
+            };
+            let _: () = conn
+                .hset(
+                    self.dlq_key(),
+                    dead_letter.action.id.to_string(),
+                    serde_json::to_string(&dead_letter).map_err(error::ErrorInternalServerError)?,
+                )
+                .map_err(error::ErrorInternalServerError)?;
+            Ok(None)
+        } else {
+            let retry_at = Utc::now() + RetryQueue::backoff(action.attempts);
+            self.defer(&action, retry_at)?;
+            Ok(Some(retry_at))
+        }
+    }
+
+    /// Puts an action aside until `until`, without counting it as an attempt.
+    pub fn defer(&self, action: &QueueAction, until: DateTime<Utc>) -> Result<(), Error> {
+        let conn = self
+            .client
+            .get_connection()
+            .map_err(error::ErrorInternalServerError)?;
+        let _: () = conn
+            .zadd(
+                self.delayed_key(),
+                serde_json::to_string(action).map_err(error::ErrorInternalServerError)?,
+                until.timestamp(),
+            )
+            .map_err(error::ErrorInternalServerError)?;
+
+        Ok(())
+    }
+
+    /// Moves every retry that's due back onto `queue`. Safe to call from several workers at once,
+    /// since only the one that manages to remove an action pushes it.
+    pub fn promote_due(&self, queue: &Queue) -> Result<usize, Error> {
+        let conn = self
+            .client
+            .get_connection()
+            .map_err(error::ErrorInternalServerError)?;
+        let due: Vec<(String, i64)> = conn
+            .zrangebyscore_withscores(self.delayed_key(), "-inf", Utc::now().timestamp())
+            .map_err(error::ErrorInternalServerError)?;
+
+        let mut promoted = 0;
+        for (member, due_at) in due {
+            let removed: usize = conn
+                .zrem(self.delayed_key(), &member)
+                .map_err(error::ErrorInternalServerError)?;
+            if removed == 0 {
+                continue;
+            }
+
+            let action: QueueAction =
+                serde_json::from_str(&member).map_err(error::ErrorInternalServerError)?;
+            // it's out of the set already, so put it back for the next round rather than lose it
+            if let Err(e) = queue.push(action) {
+                let _: () = conn
+                    .zadd(self.delayed_key(), &member, due_at)
+                    .map_err(error::ErrorInternalServerError)?;
+                return Err(error::ErrorInternalServerError(e));
+            }
+            promoted += 1;
+        }
+
+        Ok(promoted)
+    }
+
+    /// Every dead letter, oldest failure first.
+    pub fn dead_letters(&self) -> Result<Vec<DeadLetter>, Error> {
+        let conn = self
+            .client
+            .get_connection()
+            .map_err(error::ErrorInternalServerError)?;
+        let entries: HashMap<String, String> = conn
+            .hgetall(self.dlq_key())
+            .map_err(error::ErrorInternalServerError)?;
+
+        let mut dead_letters = entries
+            .values()
+            .map(|entry| serde_json::from_str(entry).map_err(error::ErrorInternalServerError))
+            .collect::<Result<Vec<DeadLetter>, Error>>()?;
+        dead_letters.sort_by_key(|d| d.failed_at);
+
+        Ok(dead_letters)
+    }
+
+    pub fn dead_letter(&self, id: &Uuid) -> Result<Option<DeadLetter>, Error> {
+        let conn = self
+            .client
+            .get_connection()
+            .map_err(error::ErrorInternalServerError)?;
+        let entry: Option<String> = conn
+            .hget(self.dlq_key(), id.to_string())
+            .map_err(error::ErrorInternalServerError)?;
+
+        match entry {
+            Some(entry) => Ok(Some(
+                serde_json::from_str(&entry).map_err(error::ErrorInternalServerError)?,
+            )),
+            None => Ok(None),
+        }
+    }
+
+    /// Pushes a dead letter back onto `queue` with a fresh set of attempts, and resets its job.
+    pub fn requeue(&self, conn: &PgConnection, id: &Uuid, queue: &Queue) -> Result<bool, Error> {
+        let dead_letter = match self.dead_letter(id)? {
+            Some(dead_letter) => dead_letter,
+            None => return Ok(false),
+        };
+
+        let action = QueueAction {
+            attempts: 0,
+            ..dead_letter.action
+        };
+        Job::enqueue(conn, queue, action)?;
+        self.purge(Some(id))?;
+
+        Ok(true)
+    }
+
+    /// Deletes one dead letter, or all of them. Returns how many were deleted.
+    pub fn purge(&self, id: Option<&Uuid>) -> Result<usize, Error> {
+        let conn = self
+            .client
+            .get_connection()
+            .map_err(error::ErrorInternalServerError)?;
+
+        match id {
+            Some(id) => conn.hdel(self.dlq_key(), id.to_string()),
+            None => {
+                let count: usize = conn
+                    .hlen(self.dlq_key())
+                    .map_err(error::ErrorInternalServerError)?;
+                conn.del(self.dlq_key()).map(|()| count)
+            }
+        }
+        .map_err(error::ErrorInternalServerError)
+    }
+}
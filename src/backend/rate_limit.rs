@@ -0,0 +1,175 @@
+//! Per-user rate limits for provider APIs, shared by every worker through redis. Each
+//! `<prefix>:<service>:<user_id>` hash is a token bucket that refills continuously over the
+//! provider's window, and that can be blocked outright when the provider tells us we're out.
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use redis::{Client, Commands, Script};
+use std::fmt::{self, Display};
+use uuid::Uuid;
+
+/// Fitbit allows 150 requests per user per hour.
+pub static FITBIT_REQUESTS_PER_HOUR: u32 = 150;
+
+static KEY_PREFIX: &'static str = "ratelimit";
+
+/// Takes a token if there's one. Returns 0 if it did, otherwise the unix timestamp of when to try
+/// again. Tokens are fractional, so the bucket is stored as strings.
+static ACQUIRE_SCRIPT: &'static str = r"
+local capacity = tonumber(ARGV[1])
+local refill_per_second = tonumber(ARGV[2])
+local now = tonumber(ARGV[3])
+
+local state = redis.call('HMGET', KEYS[1], 'tokens', 'updated_at', 'blocked_until')
+local tokens = tonumber(state[1]) or capacity
+local updated_at = tonumber(state[2]) or now
+local blocked_until = tonumber(state[3]) or 0
+
+if blocked_until > now then
+  return blocked_until
+end
+
+tokens = math.min(capacity, tokens + (now - updated_at) * refill_per_second)
+if tokens < 1 then
+  return now + math.ceil((1 - tokens) / refill_per_second)
+end
+
+redis.call('HMSET', KEYS[1], 'tokens', tostring(tokens - 1), 'updated_at', tostring(now))
+redis.call('EXPIRE', KEYS[1], ARGV[4])
+return 0
+";
+
+/// A request that wasn't made because the user is out of requests until `until`. Workers defer
+/// the action instead of failing it.
+#[derive(Debug, Clone)]
+pub struct RateLimited {
+    pub service: String,
+    pub user_id: Uuid,
+    pub until: DateTime<Utc>,
+}
+
+impl Display for RateLimited {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} rate limit for {} resets at {}",
+            self.service, self.user_id, self.until
+        )
+    }
+}
+
+impl std::error::Error for RateLimited {}
+
+impl ResponseError for RateLimited {
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::new(StatusCode::TOO_MANY_REQUESTS)
+    }
+}
+
+/// Picks the `RateLimited` out of an error, if that's what it is.
+pub fn rate_limited(e: &actix_web::Error) -> Option<&RateLimited> {
+    e.as_fail().downcast_ref::<RateLimited>()
+}
+
+pub struct RateLimiter {
+    client: Client,
+    service: String,
+    capacity: u32,
+    window: Duration,
+}
+
+impl RateLimiter {
+    pub fn new(redis_url: &str, service: &str, capacity: u32, window: Duration) -> RateLimiter {
+        let client = Client::open(redis_url).expect("Failed to connect to redis");
+        RateLimiter {
+            client,
+            service: service.to_string(),
+            capacity,
+            window,
+        }
+    }
+
+    fn key(&self, user_id: &Uuid) -> String {
+        format!("{}:{}:{}", KEY_PREFIX, self.service, user_id)
+    }
+
+    fn limited(&self, user_id: &Uuid, until: DateTime<Utc>) -> actix_web::Error {
+        RateLimited {
+            service: self.service.clone(),
+            user_id: *user_id,
+            until,
+        }
+        .into()
+    }
+
+    /// Takes one request from the user's bucket, or fails with `RateLimited`.
+    pub fn acquire(&self, user_id: &Uuid) -> Result<(), actix_web::Error> {
+        let conn = self
+            .client
+            .get_connection()
+            .map_err(actix_web::error::ErrorInternalServerError)?;
+        let refill_per_second = f64::from(self.capacity) / self.window.num_seconds() as f64;
+
+        let retry_at: i64 = Script::new(ACQUIRE_SCRIPT)
+            .key(self.key(user_id))
+            .arg(self.capacity)
+            .arg(refill_per_second)
+            .arg(Utc::now().timestamp())
+            .arg(self.window.num_seconds() * 2)
+            .invoke(&conn)
+            .map_err(actix_web::error::ErrorInternalServerError)?;
+
+        match retry_at {
+            0 => Ok(()),
+            retry_at => Err(self.limited(user_id, Utc.timestamp(retry_at, 0))),
+        }
+    }
+
+    /// Trusts the provider's own count over ours: the bucket is set to what's `remaining`, and
+    /// blocked until the window resets (`reset` from now) if that's nothing.
+    pub fn record_remaining(
+        &self,
+        user_id: &Uuid,
+        remaining: u32,
+        reset: Duration,
+    ) -> Result<(), actix_web::Error> {
+        let conn = self
+            .client
+            .get_connection()
+            .map_err(actix_web::error::ErrorInternalServerError)?;
+        let now = Utc::now().timestamp();
+
+        let mut fields = vec![
+            ("tokens", remaining.to_string()),
+            ("updated_at", now.to_string()),
+        ];
+        if remaining == 0 {
+            fields.push(("blocked_until", (now + reset.num_seconds()).to_string()));
+        }
+
+        let _: () = conn
+            .hset_multiple(self.key(user_id), &fields)
+            .map_err(actix_web::error::ErrorInternalServerError)?;
+        let _: () = conn
+            .expire(self.key(user_id), (self.window.num_seconds() * 2) as usize)
+            .map_err(actix_web::error::ErrorInternalServerError)?;
+
+        Ok(())
+    }
+
+    /// Blocks the user until `until`, after the provider turned a request down. Returns the
+    /// `RateLimited` error to fail the request with.
+    pub fn block(&self, user_id: &Uuid, until: DateTime<Utc>) -> actix_web::Error {
+        let blocked: Result<(), _> = self
+            .client
+            .get_connection()
+            .and_then(|conn| conn.hset(self.key(user_id), "blocked_until", until.timestamp()));
+        if let Err(e) = blocked {
+            warn!(
+                "Couldn't block {} requests for {}: {}",
+                self.service, user_id, e
+            );
+        }
+
+        self.limited(user_id, until)
+    }
+}
@@ -0,0 +1,115 @@
+//! Keeps connected services in sync without anyone calling `ingestIntraday`: every tick, each
+//! user whose sync interval has passed gets their enabled metrics synced from their sync cursors
+//! through today. Metrics whose last sync is still running are left alone until it's done.
+use crate::{
+    config::Config,
+    db::{self, Conn, SyncSetting},
+    providers::fitbit::IntradayMetric,
+    queue::{self, Queue, QueueAction, QueueActionParams},
+};
+use actix_web::{error, Error};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+/// How often to look for users that are due.
+pub static TICK_SECONDS: u64 = 60;
+
+pub struct Scheduler {
+    pub queue: Queue,
+    /// A connection is checked out per tick, so the scheduler outlives database restarts
+    pub pool: db::Pool,
+}
+
+impl Scheduler {
+    fn schedule(&self, conn: &Conn, setting: &SyncSetting) -> Result<usize, Error> {
+        let mut scheduled = 0;
+        for metric in &setting.metrics {
+            let metric = match metric.parse::<IntradayMetric>() {
+                Ok(metric) => metric,
+                Err(e) => {
+                    warn!("Skipping sync for {}: {}", setting.user_id, e);
+                    continue;
+                }
+            };
+
+            // a first sync takes INITIAL_SYNC_DAYS requests, more than fit in an hour, so the
+            // next one waits for it instead of piling the same days on top
+            let pending = db::Job::sync_pending(conn, &setting.user_id, &metric)
+                .map_err(error::ErrorInternalServerError)?;
+            if pending {
+                info!(
+                    "Skipping sync of {} for {}, the last one hasn't finished",
+                    metric.as_str(),
+                    setting.user_id
+                );
+                continue;
+            }
+
+            // holes are left to syncs users ask for, looking for them every tick costs too many
+            // requests for days that are just empty
+            let action =
+                QueueAction::new(setting.user_id, QueueActionParams::SyncSince(metric, false));
+            db::Job::enqueue(conn, &self.queue, action).map_err(error::ErrorInternalServerError)?;
+            scheduled += 1;
+        }
+
+        Ok(scheduled)
+    }
+
+    /// Enqueues a sync for everyone that's due. Returns how many users were synced.
+    pub fn tick(&self) -> Result<usize, Error> {
+        let conn = Conn(self.pool.get().map_err(error::ErrorServiceUnavailable)?);
+        // fitbit is the only service we can sync so far
+        SyncSetting::create_missing(&conn, "fitbit").map_err(error::ErrorInternalServerError)?;
+        let due = SyncSetting::claim_due(&conn).map_err(error::ErrorInternalServerError)?;
+
+        for setting in &due {
+            match self.schedule(&conn, setting) {
+                Ok(n) => info!("Scheduled {} syncs for {}", n, setting.user_id),
+                Err(e) => error!("Error scheduling sync for {}: {:?}", setting.user_id, e),
+            }
+        }
+
+        Ok(due.len())
+    }
+
+    /// Ticks until `shutdown` is set.
+    pub fn run(&self, shutdown: &AtomicBool) {
+        while !shutdown.load(Ordering::SeqCst) {
+            if let Err(e) = self.tick() {
+                error!("Error running scheduler: {:?}", e);
+            }
+
+            // sleep in small steps so shutting down doesn't wait for a whole tick
+            for _ in 0..TICK_SECONDS {
+                if shutdown.load(Ordering::SeqCst) {
+                    break;
+                }
+                thread::sleep(std::time::Duration::from_secs(1));
+            }
+        }
+    }
+}
+
+pub fn spawn_scheduler(
+    config: &Config,
+    pool: &db::Pool,
+    shutdown: &Arc<AtomicBool>,
+) -> thread::JoinHandle<()> {
+    let config = config.clone();
+    let pool = pool.clone();
+    let shutdown = shutdown.clone();
+
+    thread::spawn(move || {
+        info!("Started scheduler");
+
+        let scheduler = Scheduler {
+            queue: queue::init_queue(&config.redis_url, config.queue_name.clone()),
+            pool,
+        };
+        scheduler.run(&shutdown);
+
+        info!("Scheduler shut down");
+    })
+}
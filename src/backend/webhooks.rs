@@ -0,0 +1,231 @@
+//! Push-based sync: Fitbit notifies `/webhooks/fitbit` whenever a subscribed user's data changes,
+//! and each notification is turned into the queue actions that ingest that day.
+use actix::prelude::*;
+use actix_web::{
+    error, AsyncResponder, Error, FromRequest, FutureResponse, HttpMessage, HttpRequest,
+    HttpResponse, Query,
+};
+use chrono::NaiveDate;
+use diesel::OptionalExtension;
+use futures::future::Future;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use std::collections::HashMap;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::{
+    crypto::Keyring,
+    db::{self, Job, SyncSetting, Token},
+    providers::fitbit::IntradayMetric,
+    queue::{Queue, QueueAction, QueueActionParams},
+    AppState,
+};
+
+type HmacSha1 = Hmac<Sha1>;
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FitbitNotification {
+    pub collection_type: String,
+    pub date: NaiveDate,
+    pub owner_id: String,
+    pub owner_type: String,
+    pub subscription_id: String,
+}
+
+pub struct WebhookExecutor {
+    pool: db::Pool,
+    producer: Queue,
+    keyring: Arc<Keyring>,
+    fitbit_secret: String,
+    fitbit_verification_code: Option<String>,
+}
+
+impl WebhookExecutor {
+    pub fn new(
+        pool: db::Pool,
+        producer: Queue,
+        keyring: Arc<Keyring>,
+        fitbit_secret: &str,
+        fitbit_verification_code: Option<String>,
+    ) -> WebhookExecutor {
+        WebhookExecutor {
+            pool,
+            producer,
+            keyring,
+            fitbit_secret: fitbit_secret.to_owned(),
+            fitbit_verification_code,
+        }
+    }
+
+    /// Fitbit signs the body with HMAC-SHA1, keyed by our client secret and a `&`.
+    fn verify_signature(&self, body: &[u8], signature: &str) -> bool {
+        let expected = match base64::decode(signature) {
+            Ok(expected) => expected,
+            Err(_) => return false,
+        };
+        let mut mac = HmacSha1::new_varkey(format!("{}&", self.fitbit_secret).as_bytes())
+            .expect("HMAC takes keys of any size");
+        mac.input(body);
+
+        mac.verify(&expected).is_ok()
+    }
+
+    /// The actions that re-ingest whatever a notification says changed.
+    fn actions_for(
+        conn: &db::Conn,
+        user_id: Uuid,
+        notification: &FitbitNotification,
+    ) -> Result<Vec<QueueAction>, Error> {
+        let date = notification.date;
+        let params = match notification.collection_type.as_str() {
+            "activities" => {
+                let setting = SyncSetting::find_one(conn, (&user_id, "fitbit"))
+                    .optional()
+                    .map_err(error::ErrorInternalServerError)?;
+                let metrics = match setting {
+                    Some(ref setting) if !setting.enabled => vec![],
+                    Some(setting) => setting
+                        .metrics
+                        .iter()
+                        .filter_map(|m| m.parse::<IntradayMetric>().ok())
+                        .collect(),
+                    None => IntradayMetric::all(),
+                };
+
+                metrics
+                    .into_iter()
+                    .map(|metric| QueueActionParams::IngestIntraday(metric, date))
+                    .collect()
+            }
+            "sleep" => vec![QueueActionParams::IngestSleep(date)],
+            "body" => vec![QueueActionParams::IngestBody(date, date)],
+            // foods and anything newer aren't ingested
+            _ => vec![],
+        };
+
+        Ok(params
+            .into_iter()
+            .map(|params| QueueAction::new(user_id, params))
+            .collect())
+    }
+}
+
+impl Actor for WebhookExecutor {
+    type Context = SyncContext<Self>;
+}
+
+pub struct VerifyFitbit(pub String);
+
+impl Message for VerifyFitbit {
+    type Result = bool;
+}
+
+impl Handler<VerifyFitbit> for WebhookExecutor {
+    type Result = bool;
+
+    fn handle(&mut self, msg: VerifyFitbit, _: &mut Self::Context) -> Self::Result {
+        self.fitbit_verification_code.as_ref() == Some(&msg.0)
+    }
+}
+
+pub struct FitbitNotifications {
+    pub body: Vec<u8>,
+    pub signature: Option<String>,
+}
+
+impl Message for FitbitNotifications {
+    type Result = Result<usize, Error>;
+}
+
+impl Handler<FitbitNotifications> for WebhookExecutor {
+    type Result = Result<usize, Error>;
+
+    /// Returns how many actions were enqueued.
+    fn handle(&mut self, msg: FitbitNotifications, _: &mut Self::Context) -> Self::Result {
+        let signature = msg.signature.unwrap_or_default();
+        if !self.verify_signature(&msg.body, &signature) {
+            // Fitbit's docs ask for a 404 when the signature doesn't match
+            return Err(error::ErrorNotFound("bad signature"));
+        }
+
+        let notifications: Vec<FitbitNotification> =
+            serde_json::from_slice(&msg.body).map_err(error::ErrorBadRequest)?;
+        let conn = db::Conn(self.pool.get().map_err(error::ErrorInternalServerError)?);
+
+        let mut enqueued = 0;
+        for notification in &notifications {
+            let found = Token::find_by_service_userid(
+                &conn,
+                &self.keyring,
+                "fitbit",
+                &notification.owner_id,
+            );
+            let token = match found {
+                Ok(token) => token,
+                Err(e) => {
+                    warn!(
+                        "Dropping Fitbit notification for {}: {}",
+                        notification.owner_id, e
+                    );
+                    continue;
+                }
+            };
+
+            for action in WebhookExecutor::actions_for(&conn, token.user_id, notification)? {
+                Job::enqueue(&conn, &self.producer, action)
+                    .map_err(error::ErrorInternalServerError)?;
+                enqueued += 1;
+            }
+        }
+
+        Ok(enqueued)
+    }
+}
+
+/// Fitbit checks a new subscriber endpoint with `?verify=<code>`: 204 for our code, 404 otherwise.
+pub fn fitbit_verify(req: &HttpRequest<AppState>) -> FutureResponse<HttpResponse> {
+    let executor = req.state().webhooks.clone();
+    let code = Query::<HashMap<String, String>>::extract(req)
+        .ok()
+        .and_then(|query| query.get("verify").cloned())
+        .unwrap_or_default();
+
+    executor
+        .send(VerifyFitbit(code))
+        .from_err()
+        .and_then(|verified| match verified {
+            true => Ok(HttpResponse::NoContent().finish()),
+            false => Ok(HttpResponse::NotFound().finish()),
+        })
+        .responder()
+}
+
+pub fn fitbit_notification(req: &HttpRequest<AppState>) -> FutureResponse<HttpResponse> {
+    let executor = req.state().webhooks.clone();
+    let signature = req
+        .headers()
+        .get("X-Fitbit-Signature")
+        .and_then(|value| value.to_str().ok())
+        .map(String::from);
+
+    req.body()
+        .from_err()
+        .and_then(move |body| {
+            executor
+                .send(FitbitNotifications {
+                    body: body.to_vec(),
+                    signature,
+                })
+                .from_err()
+        })
+        .and_then(|res| match res {
+            Ok(enqueued) => {
+                info!("Enqueued {} actions from Fitbit notifications", enqueued);
+                Ok(HttpResponse::NoContent().finish())
+            }
+            Err(e) => Err(e),
+        })
+        .responder()
+}
@@ -1,81 +1,386 @@
 use crate::{
-    db::{self, Calorie, Conn, Distance, Elevation, Floor, Step, Token},
+    config::Config,
+    crypto::Keyring,
+    db::{
+        self, BodyMeasurement, Calorie, Conn, Distance, Elevation, Floor, HeartRate,
+        HeartRateMinute, HeartRateZone, Measurement, RestingHeartRate, Sleep, SleepStage, Step,
+        SyncCursor, Token, Upsert, UpsertStats,
+    },
     oauth::OAuth,
     providers::fitbit,
-    queue::{Queue, QueueAction, QueueActionParams},
+    queue::{self, Queue, QueueAction, QueueActionParams, RetryQueue},
+    rate_limit::{self, RateLimiter},
 };
 use actix_web::{error, Error};
-use chrono::{Duration, NaiveDate};
+use chrono::{Duration, NaiveDate, TimeZone, Utc};
+use chrono_tz::Tz;
+use diesel::{Connection, OptionalExtension};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
 use uuid::Uuid;
 
-pub struct WorkerContext {
+/// A metric without a sync cursor is synced this many days back, today included.
+pub static INITIAL_SYNC_DAYS: i64 = 30;
+/// How far before the sync cursor to look for holes.
+pub static HOLE_LOOKBACK_DAYS: i64 = 90;
+/// After redis or postgres fail, a worker waits this long before trying again, doubling with every
+/// failure in a row up to `ERROR_BACKOFF_MAX_SECONDS`.
+pub static ERROR_BACKOFF_BASE_SECONDS: u64 = 1;
+pub static ERROR_BACKOFF_MAX_SECONDS: u64 = 60;
+
+/// What a worker thread keeps between actions.
+pub struct Worker {
     pub queue: Queue,
-    pub conn: Conn,
+    pub retries: RetryQueue,
+    /// Shared with every other worker through redis
+    pub fitbit_limiter: RateLimiter,
     pub oauth: OAuth,
+    pub keyring: Arc<Keyring>,
+    pub pool: db::Pool,
+}
+
+/// What executing one action needs. The connection is checked out for just that action, so a
+/// restarted database only fails the actions in flight.
+pub struct WorkerContext<'a> {
+    pub queue: &'a Queue,
+    pub retries: &'a RetryQueue,
+    pub fitbit_limiter: &'a RateLimiter,
+    pub conn: Conn,
+    pub oauth: &'a OAuth,
+    pub keyring: &'a Keyring,
+}
+
+impl Worker {
+    fn context(&self) -> Result<WorkerContext, Error> {
+        Ok(WorkerContext {
+            queue: &self.queue,
+            retries: &self.retries,
+            fitbit_limiter: &self.fitbit_limiter,
+            conn: Conn(self.pool.get().map_err(error::ErrorServiceUnavailable)?),
+            oauth: &self.oauth,
+            keyring: &self.keyring,
+        })
+    }
 }
 
-fn ingest_intraday_bulk(
+fn enqueue_child(
     ctx: &WorkerContext,
-    user_id: &Uuid,
-    metric: &fitbit::IntradayMetric,
+    parent: &QueueAction,
+    params: QueueActionParams,
+) -> Result<(), Error> {
+    let action = parent.child(params);
+
+    db::Job::enqueue(&ctx.conn, ctx.queue, action).map_err(error::ErrorInternalServerError)?;
+
+    Ok(())
+}
+
+/// The user's Fitbit token, refreshed if it expired.
+fn fitbit_token(ctx: &WorkerContext, user_id: &Uuid) -> Result<Token, Error> {
+    let token = ctx
+        .oauth
+        .refresh_and_update("fitbit", &ctx.conn, ctx.keyring, user_id)
+        .map_err(error::ErrorInternalServerError)?;
+    Ok(fitbit::with_stored_timezone(&ctx.conn, ctx.keyring, token))
+}
+
+fn ingest_bulk<F: Fn(NaiveDate) -> QueueActionParams>(
+    ctx: &WorkerContext,
+    parent: &QueueAction,
     start_date: NaiveDate,
     num_days: u32,
+    params_for_day: F,
 ) -> Result<(), Error> {
     for i in 0..num_days {
-        let action = QueueAction {
-            id: Uuid::new_v4(),
-            user_id: *user_id,
-            params: QueueActionParams::IngestIntraday(
-                metric.clone(),
-                start_date + Duration::days(i64::from(i)),
+        enqueue_child(
+            ctx,
+            parent,
+            params_for_day(start_date + Duration::days(i64::from(i))),
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Enqueues every day of `metric` from its sync cursor up to today, and with `fill_holes` every
+/// day before the cursor that has no data.
+fn sync_since(
+    ctx: &WorkerContext,
+    parent: &QueueAction,
+    metric: &fitbit::IntradayMetric,
+    fill_holes: bool,
+) -> Result<(), Error> {
+    let token = fitbit_token(ctx, &parent.user_id)?;
+    let tz = fitbit::local_tz(&token)?;
+    let today = Utc::now().with_timezone(&tz).date().naive_local();
+
+    let key = (&parent.user_id, "fitbit", metric.as_str());
+    let cursor = SyncCursor::find_one(&ctx.conn, key)
+        .optional()
+        .map_err(error::ErrorInternalServerError)?;
+
+    let mut days = match &cursor {
+        Some(cursor) if fill_holes => db::missing_days(
+            &ctx.conn,
+            metric.table(),
+            &parent.user_id,
+            (
+                cursor.synced_through - Duration::days(HOLE_LOOKBACK_DAYS),
+                cursor.synced_through,
             ),
-        };
+            tz,
+        )
+        .map_err(error::ErrorInternalServerError)?,
+        _ => vec![],
+    };
+    let holes = days.len();
 
-        ctx.queue
-            .push(action)
-            .map_err(error::ErrorInternalServerError)?;
+    // the cursor only moves past days that were synced after they were over, so the days that
+    // can still change are always after it
+    let mut day = match &cursor {
+        Some(cursor) => cursor.synced_through + Duration::days(1),
+        None => {
+            let first_day = today - Duration::days(INITIAL_SYNC_DAYS - 1);
+            SyncCursor::start(&ctx.conn, key, first_day - Duration::days(1))
+                .map_err(error::ErrorInternalServerError)?;
+            first_day
+        }
+    };
+    while day <= today {
+        days.push(day);
+        day = day + Duration::days(1);
+    }
+
+    info!(
+        "Syncing {} days of {} for {} ({} holes)",
+        days.len(),
+        metric.as_str(),
+        parent.user_id,
+        holes
+    );
+    for day in days {
+        enqueue_child(
+            ctx,
+            parent,
+            QueueActionParams::IngestIntraday(metric.clone(), day),
+        )?;
     }
 
     Ok(())
 }
 
-fn ingest_intraday<T: fitbit::IntradayMeasurement + db::Object>(
+/// Moves the metric's sync cursor past `date` once the whole day is over. A failure only means
+/// days get synced again.
+fn advance_cursor(
+    ctx: &WorkerContext,
+    token: &Token,
+    metric: &fitbit::IntradayMetric,
+    date: NaiveDate,
+) {
+    let tz = match fitbit::local_tz(token) {
+        Ok(tz) => tz,
+        Err(e) => {
+            warn!("Couldn't advance the {} cursor: {}", metric.as_str(), e);
+            return;
+        }
+    };
+    let today = Utc::now().with_timezone(&tz).date().naive_local();
+    if date >= today {
+        return;
+    }
+
+    if let Err(e) = advance_cursor_over_run(ctx, token, metric, date, (tz, today)) {
+        warn!(
+            "Couldn't advance the {} cursor to {}: {}",
+            metric.as_str(),
+            date,
+            e
+        );
+    }
+}
+
+/// Moves the cursor over `date` and the days right after it that were already ingested after
+/// they were over. A day that finishes before an earlier one leaves the cursor alone and waits to
+/// be picked up here, so the cursor never moves past a day that failed.
+fn advance_cursor_over_run(
+    ctx: &WorkerContext,
+    token: &Token,
+    metric: &fitbit::IntradayMetric,
+    date: NaiveDate,
+    (tz, today): (Tz, NaiveDate),
+) -> Result<usize, diesel::result::Error> {
+    let key = (&token.user_id, "fitbit", metric.as_str());
+    let cursor = match SyncCursor::find_one(&ctx.conn, key).optional()? {
+        Some(cursor) => cursor,
+        None => return Ok(0),
+    };
+    if cursor.synced_through + Duration::days(1) != date {
+        return Ok(0);
+    }
+
+    let mut later = vec![];
+    let mut day = date + Duration::days(1);
+    while day < today {
+        later.push(day);
+        day = day + Duration::days(1);
+    }
+    let ingested = db::Job::ingested_days(&ctx.conn, &token.user_id, metric, &later)?;
+    let through = later
+        .into_iter()
+        .take_while(|day| {
+            ingested.iter().any(|(ingested_day, finished_at)| {
+                ingested_day == day && finished_at.with_timezone(&tz).date().naive_local() > *day
+            })
+        })
+        .last()
+        .unwrap_or(date);
+
+    SyncCursor::advance(&ctx.conn, key, (cursor.synced_through, through))
+}
+
+fn log_stats(what: &str, date: NaiveDate, stats: UpsertStats) {
+    info!(
+        "Ingested {} for {}: {} inserted, {} updated",
+        what, date, stats.inserted, stats.updated
+    );
+}
+
+/// Rollups only refresh recent days on their own. Fitbit days are local, so the refresh covers a
+/// day on either side of `date`. A failed refresh only leaves the long-range views stale.
+fn refresh_rollups(ctx: &WorkerContext, table: &str, date: NaiveDate) {
+    let start = Utc
+        .from_utc_date(&(date - Duration::days(1)))
+        .and_hms(0, 0, 0);
+    let end = Utc
+        .from_utc_date(&(date + Duration::days(1)))
+        .and_hms(0, 0, 0);
+
+    if let Err(e) = db::refresh_rollups(&ctx.conn, table, &start, &end) {
+        warn!("Couldn't refresh {} rollups for {}: {}", table, date, e);
+    }
+}
+
+fn ingest_intraday<T: fitbit::IntradayMeasurement + db::Measurement + db::Upsert>(
     ctx: &WorkerContext,
     token: &Token,
     date: NaiveDate,
 ) -> Result<(), Error> {
-    let measurement = fitbit::measurement_for_day::<T>(date, token)?;
-    T::insert_many(&ctx.conn, &measurement).map_err(error::ErrorInternalServerError)?;
+    let measurement = fitbit::measurement_for_day::<T>(date, token, &ctx.fitbit_limiter)?;
+    let stats = T::upsert_many(&ctx.conn, &measurement).map_err(error::ErrorInternalServerError)?;
+    refresh_rollups(ctx, T::TABLE, date);
+    log_stats(T::name(), date, stats);
+    Ok(())
+}
+
+fn ingest_heart_rate(ctx: &WorkerContext, token: &Token, date: NaiveDate) -> Result<(), Error> {
+    let resp = fitbit::intraday_for_day::<HeartRate>(date, token, &ctx.fitbit_limiter)?;
+    let (resting, zones) = fitbit::heart_rate_summary(date, token, &resp)?;
+    let heart_rates = fitbit::measurements_from_response::<HeartRate>(date, token, resp)?;
+    // the per-minute series is a second request, fitbit doesn't return both at once
+    let heart_rate_minutes =
+        fitbit::measurement_for_day::<HeartRateMinute>(date, token, &ctx.fitbit_limiter)?;
+
+    let stats = ctx
+        .conn
+        .transaction::<_, diesel::result::Error, _>(|| {
+            let stats = HeartRate::upsert_many(&ctx.conn, &heart_rates)?;
+            HeartRateMinute::upsert_many(&ctx.conn, &heart_rate_minutes)?;
+            RestingHeartRate::upsert_many(&ctx.conn, &resting.into_iter().collect::<Vec<_>>())?;
+            HeartRateZone::upsert_many(&ctx.conn, &zones)?;
+            Ok(stats)
+        })
+        .map_err(error::ErrorInternalServerError)?;
+
+    refresh_rollups(ctx, HeartRate::TABLE, date);
+    log_stats("heart rate", date, stats);
     Ok(())
 }
 
-fn execute_one(
+fn ingest_sleep(ctx: &WorkerContext, token: &Token, date: NaiveDate) -> Result<(), Error> {
+    let sleeps = fitbit::sleep_for_day(date, token, &ctx.fitbit_limiter)?;
+
+    let stats = ctx
+        .conn
+        .transaction::<_, diesel::result::Error, _>(|| {
+            let mut stats = UpsertStats::default();
+            for (sleep, stages) in &sleeps {
+                let sleep_stats = Sleep::upsert_many(&ctx.conn, &[sleep.clone()])?;
+                stats.inserted += sleep_stats.inserted;
+                stats.updated += sleep_stats.updated;
+
+                SleepStage::replace_for_period(
+                    &ctx.conn,
+                    &sleep.user_id,
+                    &sleep.start_time,
+                    &sleep.end_time,
+                    stages,
+                )?;
+            }
+            Ok(stats)
+        })
+        .map_err(error::ErrorInternalServerError)?;
+
+    log_stats("sleep", date, stats);
+    Ok(())
+}
+
+fn ingest_body(
     ctx: &WorkerContext,
-    user_id: &Uuid,
-    action: &QueueActionParams,
+    token: &Token,
+    start_date: NaiveDate,
+    end_date: NaiveDate,
 ) -> Result<(), Error> {
-    match action {
+    let measurements =
+        fitbit::body_measurements_for_range(start_date, end_date, token, &ctx.fitbit_limiter)?;
+    let stats = BodyMeasurement::upsert_many(&ctx.conn, &measurements)
+        .map_err(error::ErrorInternalServerError)?;
+    log_stats("body measurements", start_date, stats);
+    Ok(())
+}
+
+fn execute_one(ctx: &WorkerContext, action: &QueueAction) -> Result<(), Error> {
+    let user_id = &action.user_id;
+    match &action.params {
         QueueActionParams::IngestIntraday(metric, date) => {
-            let token = ctx
-                .oauth
-                .refresh_and_update("fitbit", &ctx.conn, user_id)
-                .map_err(error::ErrorInternalServerError)?;
-            match metric {
+            let token = fitbit_token(ctx, user_id)?;
+            let result = match metric {
                 fitbit::IntradayMetric::Step => ingest_intraday::<Step>(ctx, &token, *date),
-                fitbit::IntradayMetric::Calorie => {
-                    ingest_intraday::<Calorie>(ctx, &token, *date)
-                }
-                fitbit::IntradayMetric::Distance => {
-                    ingest_intraday::<Distance>(ctx, &token, *date)
-                }
+                fitbit::IntradayMetric::Calorie => ingest_intraday::<Calorie>(ctx, &token, *date),
+                fitbit::IntradayMetric::Distance => ingest_intraday::<Distance>(ctx, &token, *date),
                 fitbit::IntradayMetric::Elevation => {
                     ingest_intraday::<Elevation>(ctx, &token, *date)
                 }
                 fitbit::IntradayMetric::Floor => ingest_intraday::<Floor>(ctx, &token, *date),
+                fitbit::IntradayMetric::HeartRate => ingest_heart_rate(ctx, &token, *date),
+            };
+            if result.is_ok() {
+                advance_cursor(ctx, &token, metric, *date);
             }
+            result
         }
         QueueActionParams::BulkIngestIntraday(metric, start_date, num_days) => {
-            ingest_intraday_bulk(ctx, user_id, metric, *start_date, *num_days)
+            ingest_bulk(ctx, action, *start_date, *num_days, |date| {
+                QueueActionParams::IngestIntraday(metric.clone(), date)
+            })
+        }
+        QueueActionParams::IngestSleep(date) => {
+            let token = fitbit_token(ctx, user_id)?;
+            ingest_sleep(ctx, &token, *date)
+        }
+        QueueActionParams::BulkIngestSleep(start_date, num_days) => ingest_bulk(
+            ctx,
+            action,
+            *start_date,
+            *num_days,
+            QueueActionParams::IngestSleep,
+        ),
+        QueueActionParams::IngestBody(start_date, end_date) => {
+            let token = fitbit_token(ctx, user_id)?;
+            ingest_body(ctx, &token, *start_date, *end_date)
+        }
+        QueueActionParams::SyncSince(metric, fill_holes) => {
+            sync_since(ctx, action, metric, *fill_holes)
         }
     }
 }
@@ -83,22 +388,72 @@ fn execute_one(
 pub fn pop_and_execute(ctx: &WorkerContext) -> Result<Option<()>, Error> {
     if let Some(task) = ctx.queue.next::<QueueAction>(1) {
         let task = task.map_err(error::ErrorInternalServerError)?;
-        let QueueAction {
+        let action = task.inner().clone();
+        let id = &action.id;
+
+        let cancelled = db::Job::find_one(&ctx.conn, id)
+            .ok()
+            .and_then(|job| job.status())
+            .map_or(false, |status| status == db::JobStatus::Cancelled);
+        if cancelled {
+            info!("Skipping cancelled task {}", id);
+            return Ok(Some(()));
+        }
+
+        info!(
+            "Processing task {} (attempt {})...",
             id,
-            user_id,
-            params,
-        } = task.inner();
+            action.attempts + 1
+        );
+        if let Err(e) = db::Job::start(&ctx.conn, &action) {
+            warn!("Couldn't record the start of task {}: {:?}", id, e);
+        }
 
-        info!("Processing task {}...", id);
-        match execute_one(ctx, user_id, &params) {
+        match execute_one(ctx, &action) {
             Ok(_) => {
                 info!("Processed task {}", id);
+                if let Err(e) = db::Job::succeed(&ctx.conn, id) {
+                    warn!("Couldn't record the success of task {}: {:?}", id, e);
+                }
                 Ok(Some(()))
             }
             Err(e) => {
+                if let Some(limited) = rate_limit::rate_limited(&e) {
+                    // running out of requests isn't the action's fault, so it keeps its attempts
+                    info!("Deferring task {}: {}", id, limited);
+                    if let Err(defer_err) = ctx.retries.defer(&action, limited.until) {
+                        error!("Couldn't defer task {}: {:?}", id, defer_err);
+                        task.fail();
+                    }
+                    if let Err(job_err) = db::Job::defer(&ctx.conn, id, &limited.until) {
+                        warn!("Couldn't record the deferral of task {}: {:?}", id, job_err);
+                    }
+                    return Ok(Some(()));
+                }
+
                 error!("Error processing task {}: {:?}", id, e);
-                task.fail();
-                Err(e)
+                // the task is done with either way, unless we can't even record the failure
+                let retrying = match ctx.retries.fail(action.clone(), &e.to_string()) {
+                    Ok(Some(retry_at)) => {
+                        info!("Retrying task {} at {}", id, retry_at);
+                        true
+                    }
+                    Ok(None) => {
+                        warn!("Task {} failed too many times, moved to the DLQ", id);
+                        false
+                    }
+                    Err(retry_err) => {
+                        error!("Couldn't reschedule task {}: {:?}", id, retry_err);
+                        task.fail();
+                        false
+                    }
+                };
+                let job_result =
+                    db::Job::fail(&ctx.conn, id, action.attempts + 1, &e.to_string(), retrying);
+                if let Err(job_err) = job_result {
+                    warn!("Couldn't record the failure of task {}: {:?}", id, job_err);
+                }
+                Ok(Some(()))
             }
         }
     } else {
@@ -106,3 +461,70 @@ pub fn pop_and_execute(ctx: &WorkerContext) -> Result<Option<()>, Error> {
         Ok(None)
     }
 }
+
+/// Sleeps for the backoff after `failures` failures in a row, waking up early on shutdown.
+fn back_off(failures: u32, shutdown: &AtomicBool) {
+    let seconds = ERROR_BACKOFF_BASE_SECONDS
+        .checked_shl(failures.saturating_sub(1))
+        .unwrap_or(ERROR_BACKOFF_MAX_SECONDS)
+        .min(ERROR_BACKOFF_MAX_SECONDS);
+    for _ in 0..seconds {
+        if shutdown.load(Ordering::SeqCst) {
+            return;
+        }
+        thread::sleep(std::time::Duration::from_secs(1));
+    }
+}
+
+/// Executes actions until `shutdown` is set. It's only checked between actions, so the one in
+/// flight is always finished.
+pub fn run(worker: &Worker, shutdown: &AtomicBool) {
+    let mut failures = 0;
+    while !shutdown.load(Ordering::SeqCst) {
+        if let Err(e) = worker.retries.promote_due(&worker.queue) {
+            error!("Error promoting retries: {:?}", e);
+        }
+
+        // failed actions are retried on their own, so only redis and postgres being unavailable
+        // end up here. `next` already waits a second when idle.
+        match worker.context().and_then(|ctx| pop_and_execute(&ctx)) {
+            Ok(_) => failures = 0,
+            Err(e) => {
+                failures += 1;
+                error!("Worker error ({} in a row): {:?}", failures, e);
+                back_off(failures, shutdown);
+            }
+        }
+    }
+}
+
+/// Starts `config.num_workers` threads, each with its own queue.
+pub fn spawn_workers(
+    config: &Config,
+    pool: &db::Pool,
+    shutdown: &Arc<AtomicBool>,
+) -> Vec<thread::JoinHandle<()>> {
+    (0..config.num_workers)
+        .map(|i| {
+            let config = config.clone();
+            let pool = pool.clone();
+            let shutdown = shutdown.clone();
+
+            thread::spawn(move || {
+                info!("Started worker {}", i);
+
+                let worker = Worker {
+                    queue: queue::init_queue(&config.redis_url, config.queue_name.clone()),
+                    retries: config.retry_queue(),
+                    fitbit_limiter: config.fitbit_rate_limiter(),
+                    oauth: config.oauth(),
+                    keyring: config.token_keyring.clone(),
+                    pool,
+                };
+                run(&worker, &shutdown);
+
+                info!("Worker {} shut down", i);
+            })
+        })
+        .collect()
+}